@@ -1,10 +1,13 @@
 use crate::app::AppState;
+use crate::raytracer::tone_mapping::ToneMapOperator;
 use egui::Ui;
 use egui::emath::Numeric;
 use glam::Vec3;
 
 impl AppState {
     pub fn prepare_egui(&mut self, window: &winit::window::Window) {
+        // The save borrows the whole state, so it runs after the GUI is built
+        let mut save_requested = false;
         self.egui_framework.prepare(&window, |egui_ctx| {
             egui::Window::new("Hello, egui!").show(egui_ctx, |ui| {
                 ui.horizontal(|ui| {
@@ -91,6 +94,38 @@ impl AppState {
                     }
                 });
 
+                ui.horizontal(|ui| {
+                    ui.label("Tone mapping:");
+                    egui::ComboBox::from_id_salt("tone_mapping")
+                        .selected_text(self.tone_mapping.operator.name())
+                        .show_ui(ui, |ui| {
+                            for operator in ToneMapOperator::ALL {
+                                ui.selectable_value(
+                                    &mut self.tone_mapping.operator,
+                                    operator,
+                                    operator.name(),
+                                );
+                            }
+                        });
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Exposure:");
+                    ui.add(
+                        egui::DragValue::new(&mut self.tone_mapping.exposure)
+                            .speed(0.05)
+                            .range(-10.0..=10.0)
+                            .suffix(" EV"),
+                    );
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Gamma:");
+                    ui.add(
+                        egui::DragValue::new(&mut self.tone_mapping.gamma)
+                            .speed(0.01)
+                            .range(0.1..=5.0),
+                    );
+                });
+
                 ui.horizontal(|ui| {
                     ui.label("Render progress:");
                     let progress = self.render_state.progress(self.samples_per_pixel);
@@ -101,12 +136,36 @@ impl AppState {
                             .desired_width(150.0),
                     );
                 });
+                ui.horizontal(|ui| {
+                    let stats = self.scene.tracer.stats();
+                    ui.label("Tracer:");
+                    ui.label(format!(
+                        "{} ({} primitives, built in {:.1?})",
+                        self.scene.tracer_backend.name, stats.primitive_count, stats.build_time
+                    ));
+                });
                 ui.horizontal(|ui| {
                     ui.label("Frame rate:");
                     ui.label(format!("{:.2} fps", self.last_fps_update.1));
                 });
+                ui.horizontal(|ui| {
+                    save_requested = ui.button("Save image (F12)").clicked();
+                    match &self.last_snapshot {
+                        Some(Ok(path)) => {
+                            ui.label(format!("Saved {}", path.display()));
+                        }
+                        Some(Err(err)) => {
+                            ui.colored_label(egui::Color32::RED, err);
+                        }
+                        None => {}
+                    }
+                });
             });
         });
+
+        if save_requested {
+            self.save_snapshot_and_report();
+        }
     }
 }
 
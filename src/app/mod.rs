@@ -1,11 +1,15 @@
 use crate::app::gui_framework::EguiFramework;
 use crate::app::renderer::Renderer;
 use crate::raytracer::Scene;
+use crate::raytracer::canvas::{Canvas, CanvasPixel};
 use crate::raytracer::loader::CameraSettings;
+use crate::raytracer::tone_mapping::ToneMapping;
+use crate::raytracer::tracer::TracerBackend;
 use crate::raytracer::world::World;
 use pollster::FutureExt;
 use rand::prelude::SliceRandom;
 use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use std::path::PathBuf;
 use std::time::{Duration, Instant};
 use winit::dpi::Size;
 use winit::event::MouseButton;
@@ -14,6 +18,7 @@ use winit::keyboard::KeyCode;
 mod gui;
 mod gui_framework;
 mod renderer;
+mod snapshot;
 mod winit_app;
 
 struct RenderState {
@@ -25,8 +30,8 @@ struct RenderState {
     current_pixel_render_order: usize,
     /// The current pixel being rendered.
     current_render_pixel: usize,
-    /// The canvas that holds the accumulated values: (r_sum, g_sum, b_sum, sample_count)
-    canvas: Vec<(f32, f32, f32, u32)>,
+    /// The canvas that holds the accumulated values.
+    canvas: Canvas,
     /// The total number of samples that have been rendered.
     /// This needs to be equal to height * width * samples_per_pixel to be considered finished.
     total_rendered_pixel_samples: usize,
@@ -50,7 +55,7 @@ impl RenderState {
     fn new(width: u32, height: u32) -> Self {
         let len = (width * height) as usize;
         let pixel_render_orders = Self::generate_pixel_render_orders(len);
-        let canvas = vec![(0.0, 0.0, 0.0, 0); len];
+        let canvas = Canvas::new(width, height);
 
         Self {
             pixel_render_orders,
@@ -79,7 +84,7 @@ impl RenderState {
     }
 
     fn total_pixels_to_render(&self, samples_per_pixel: u32) -> usize {
-        self.canvas.len() * samples_per_pixel as usize
+        self.canvas.pixel_count() * samples_per_pixel as usize
     }
 
     fn missing_pixels(&self, samples_per_pixel: u32) -> usize {
@@ -87,7 +92,7 @@ impl RenderState {
     }
 
     fn restore_canvas(&mut self) {
-        self.canvas.fill((0.0, 0.0, 0.0, 0));
+        self.canvas.clear();
         self.current_render_pixel = 0;
         self.current_pixel_render_order =
             (self.current_pixel_render_order + 1) % self.pixel_render_orders.len();
@@ -95,27 +100,16 @@ impl RenderState {
         self.total_rendered_pixel_samples = 0;
     }
 
+    /// Number of samples that every pixel has received.
+    fn completed_samples(&self) -> u32 {
+        (self.total_rendered_pixel_samples / self.canvas.pixel_count()) as u32
+    }
+
     fn progress(&self, samples_per_pixel: u32) -> f32 {
         self.total_rendered_pixel_samples as f32
             / self.total_pixels_to_render(samples_per_pixel) as f32
     }
 
-    fn to_bytes(&self) -> Vec<u8> {
-        let mut bytes = Vec::with_capacity(self.canvas.len() * 4);
-        for (r_sum, g_sum, b_sum, sample_count) in &self.canvas {
-            if *sample_count > 0 {
-                let r = ((r_sum / *sample_count as f32) * 255.0).clamp(0.0, 255.0) as u8;
-                let g = ((g_sum / *sample_count as f32) * 255.0).clamp(0.0, 255.0) as u8;
-                let b = ((b_sum / *sample_count as f32) * 255.0).clamp(0.0, 255.0) as u8;
-                let a = 255u8;
-                bytes.extend_from_slice(&[r, g, b, a]);
-            } else {
-                bytes.extend_from_slice(&[0, 0, 0, 255]);
-            }
-        }
-        bytes
-    }
-
     fn on_resize(&mut self, width: u32, height: u32) {
         let len = (width * height) as usize;
         for pixel_render_order in self.pixel_render_orders.iter_mut() {
@@ -124,7 +118,7 @@ impl RenderState {
             pixel_render_order.shuffle(&mut rand::rng());
         }
 
-        self.canvas.resize(len, (0.0, 0.0, 0.0, 0));
+        self.canvas.resize(width, height);
         self.restore_canvas();
     }
 }
@@ -134,6 +128,12 @@ struct AppState {
     samples_per_pixel: u32,
     max_ray_depth: u32,
     time_budget_ms: u64,
+    tone_mapping: ToneMapping,
+    /// Scene file and output path recorded when saving snapshots.
+    scene_path: PathBuf,
+    output: PathBuf,
+    /// Outcome of the last snapshot, shown in the GUI.
+    last_snapshot: Option<Result<PathBuf, String>>,
     scene: Scene,
     last_fps_update: (Instant, f64),
     last_frame: Instant,
@@ -141,20 +141,34 @@ struct AppState {
     egui_framework: EguiFramework,
 }
 
+pub(crate) struct AppSettings {
+    /// Initial inner size of the window, or the platform default if `None`.
+    pub window_size: Option<(u32, u32)>,
+    pub samples_per_pixel: u32,
+    pub max_ray_depth: u32,
+    pub time_budget_ms: u64,
+    pub tone_mapping: ToneMapping,
+    pub scene_path: PathBuf,
+    /// Snapshots are saved next to this path, with a timestamp appended to its name.
+    pub output: PathBuf,
+}
+
 pub(crate) fn run(
     world: World,
     camera_settings: CameraSettings,
-    tracer_type: crate::TracerType,
+    tracer_backend: &'static TracerBackend,
+    settings: AppSettings,
 ) {
     let app = winit_app::WinitApp::new(
         |event_loop| {
-            event_loop
-                .create_window(
-                    winit::window::WindowAttributes::default()
-                        .with_title("Raynaldo")
-                        .with_min_inner_size(Size::Physical((100, 100).into())),
-                )
-                .unwrap()
+            let mut attributes = winit::window::WindowAttributes::default()
+                .with_title("Raynaldo")
+                .with_min_inner_size(Size::Physical((100, 100).into()));
+            if let Some(window_size) = settings.window_size {
+                attributes = attributes.with_inner_size(Size::Physical(window_size.into()));
+            }
+
+            event_loop.create_window(attributes).unwrap()
         },
         |event_loop, window| {
             let scale_factor = window.scale_factor() as f32;
@@ -170,13 +184,17 @@ pub(crate) fn run(
 
             let state = AppState {
                 render_state: RenderState::new(renderer.width(), renderer.height()),
-                samples_per_pixel: 5,
-                max_ray_depth: 10,
-                time_budget_ms: 10,
+                samples_per_pixel: settings.samples_per_pixel,
+                max_ray_depth: settings.max_ray_depth,
+                time_budget_ms: settings.time_budget_ms,
+                tone_mapping: settings.tone_mapping,
+                scene_path: settings.scene_path.clone(),
+                output: settings.output.clone(),
+                last_snapshot: None,
                 scene: Scene::new(
                     camera_settings.to_camera(renderer.width(), renderer.height(), 2.0),
                     world.clone(),
-                    tracer_type,
+                    tracer_backend,
                 ),
                 last_fps_update: (Instant::now(), 0.0),
                 last_frame: Instant::now(),
@@ -191,6 +209,10 @@ pub(crate) fn run(
                 event_loop.exit();
             }
 
+            if input.key_pressed(KeyCode::F12) {
+                state.save_snapshot_and_report();
+            }
+
             if let Some(size) = input.window_resized() {
                 if size.width != 0 && size.height != 0 {
                     state.renderer.update_size(size.width, size.height);
@@ -249,7 +271,7 @@ pub(crate) fn run(
                 && instant.elapsed() < Duration::from_millis(state.time_budget_ms)
             {
                 #[derive(Copy, Clone)]
-                struct BufferWrapper(*mut (f32, f32, f32, u32));
+                struct BufferWrapper(*mut CanvasPixel);
 
                 // SAFETY: this is safe because no simultaneous access to the same index happens
                 unsafe impl Send for BufferWrapper {}
@@ -277,10 +299,7 @@ pub(crate) fn run(
                             let buffer_ptr = buffer_ptr;
                             let buffer_ptr = buffer_ptr.0.add(index_in_buffer);
 
-                            (*buffer_ptr).0 += color.x;
-                            (*buffer_ptr).1 += color.y;
-                            (*buffer_ptr).2 += color.z;
-                            (*buffer_ptr).3 += 1;
+                            Canvas::accumulate(&mut *buffer_ptr, color);
                         }
                     });
 
@@ -297,7 +316,7 @@ pub(crate) fn run(
                 }
             }
 
-            let canvas_bytes = state.render_state.to_bytes();
+            let canvas_bytes = state.render_state.canvas.to_bytes(&state.tone_mapping);
             state
                 .renderer
                 .render_with(&canvas_bytes, |renderer, encoder, view| {
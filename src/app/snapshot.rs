@@ -0,0 +1,94 @@
+use crate::app::AppState;
+use crate::raytracer::loader::CameraSettings;
+use crate::raytracer::tone_mapping::ToneMapping;
+use anyhow::Context;
+use serde::Serialize;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Written next to the saved images so the render can be reproduced.
+/// The camera table uses the same format as the scene files.
+#[derive(Serialize)]
+struct SnapshotInfo<'a> {
+    scene: &'a str,
+    camera: CameraSettings,
+    render: RenderInfo<'a>,
+}
+
+#[derive(Serialize)]
+struct RenderInfo<'a> {
+    tracer: &'a str,
+    width: u32,
+    height: u32,
+    /// Samples every pixel has received when the image was saved.
+    samples_per_pixel: u32,
+    target_samples_per_pixel: u32,
+    max_ray_depth: u32,
+    tone_mapping: ToneMapping,
+}
+
+impl AppState {
+    /// Saves the current accumulation as a tone mapped PNG, the linear radiance as an EXR and
+    /// a TOML sidecar with the render settings. Returns the path of the PNG.
+    pub fn save_snapshot(&self) -> anyhow::Result<PathBuf> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let stem = self
+            .output
+            .file_stem()
+            .unwrap_or("render".as_ref())
+            .to_string_lossy();
+
+        // Never overwrite an earlier snapshot, even one saved in the same millisecond
+        let mut base = self.output.with_file_name(format!("{stem}-{timestamp}"));
+        for index in 1.. {
+            if !base.with_extension("png").exists() {
+                break;
+            }
+            base = self
+                .output
+                .with_file_name(format!("{stem}-{timestamp}-{index}"));
+        }
+
+        let canvas = &self.render_state.canvas;
+        let png_path = base.with_extension("png");
+        let exr_path = base.with_extension("exr");
+        let info_path = base.with_extension("toml");
+
+        canvas
+            .save(&png_path, &self.tone_mapping)
+            .with_context(|| format!("failed to save {}", png_path.display()))?;
+        canvas
+            .save(&exr_path, &self.tone_mapping)
+            .with_context(|| format!("failed to save {}", exr_path.display()))?;
+
+        let info = SnapshotInfo {
+            scene: &self.scene_path.to_string_lossy(),
+            camera: CameraSettings::from(&self.scene.camera),
+            render: RenderInfo {
+                tracer: self.scene.tracer_backend.name,
+                width: self.scene.camera.image_width,
+                height: self.scene.camera.image_height,
+                samples_per_pixel: self.render_state.completed_samples(),
+                target_samples_per_pixel: self.samples_per_pixel,
+                max_ray_depth: self.max_ray_depth,
+                tone_mapping: self.tone_mapping,
+            },
+        };
+        std::fs::write(&info_path, toml::to_string_pretty(&info)?)
+            .with_context(|| format!("failed to save {}", info_path.display()))?;
+
+        Ok(png_path)
+    }
+
+    pub fn save_snapshot_and_report(&mut self) {
+        let result = self.save_snapshot().map_err(|err| format!("{err:#}"));
+        match &result {
+            Ok(path) => eprintln!("Saved snapshot to {}", path.display()),
+            Err(err) => eprintln!("Failed to save snapshot: {err}"),
+        }
+        self.last_snapshot = Some(result);
+    }
+}
@@ -0,0 +1,64 @@
+use crate::raytracer::Scene;
+use crate::raytracer::canvas::Canvas;
+use crate::raytracer::loader::CameraSettings;
+use crate::raytracer::tone_mapping::ToneMapping;
+use crate::raytracer::tracer::TracerBackend;
+use crate::raytracer::world::World;
+use rayon::iter::{IndexedParallelIterator, IntoParallelRefMutIterator, ParallelIterator};
+use std::path::Path;
+use std::time::Instant;
+
+pub struct HeadlessSettings<'a> {
+    pub output: &'a Path,
+    pub width: u32,
+    pub height: u32,
+    pub samples_per_pixel: u32,
+    pub max_ray_depth: u32,
+    pub tone_mapping: ToneMapping,
+}
+
+/// Renders the scene without opening a window and writes the result to `settings.output`.
+pub fn run(
+    world: World,
+    camera_settings: CameraSettings,
+    tracer_backend: &'static TracerBackend,
+    settings: HeadlessSettings,
+) -> anyhow::Result<()> {
+    let camera = camera_settings.to_camera(settings.width, settings.height, 2.0);
+    let scene = Scene::new(camera, world, tracer_backend);
+    let stats = scene.tracer.stats();
+    let bounds = scene.tracer.bounds();
+    eprintln!(
+        "Built {} tracer over {} primitives in {:.1?} (scene bounds {} to {})",
+        tracer_backend.name, stats.primitive_count, stats.build_time, bounds.min, bounds.max
+    );
+    let mut canvas = Canvas::new(settings.width, settings.height);
+
+    let instant = Instant::now();
+    for sample in 0..settings.samples_per_pixel {
+        let width = canvas.width();
+        canvas
+            .pixels_mut()
+            .par_iter_mut()
+            .enumerate()
+            .for_each(|(index, pixel)| {
+                let x = index as u32 % width;
+                let y = index as u32 / width;
+                let color = scene.render_sample(x, y, settings.max_ray_depth);
+                Canvas::accumulate(pixel, color);
+            });
+
+        eprint!(
+            "\rRendered {}/{} samples per pixel ({:.1?})",
+            sample + 1,
+            settings.samples_per_pixel,
+            instant.elapsed()
+        );
+    }
+    eprintln!();
+
+    canvas.save(settings.output, &settings.tone_mapping)?;
+    eprintln!("Saved render to {}", settings.output.display());
+
+    Ok(())
+}
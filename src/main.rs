@@ -1,39 +1,106 @@
-use crate::raytracer::loader::CameraSettings;
-use crate::raytracer::world::World;
+use crate::raytracer::loader::WorldConfig;
+use crate::raytracer::tone_mapping::{ToneMapOperator, ToneMapping};
+use crate::raytracer::tracer::{self, TracerBackend};
 use clap::Parser;
+use clap::builder::{PossibleValue, PossibleValuesParser, TypedValueParser};
+use std::path::PathBuf;
 
 mod app;
+mod headless;
 mod raytracer;
 
 #[derive(Parser, Debug)]
 #[command(name = "raynaldo-reborn")]
 #[command(about = "A ray tracer with multiple backend options")]
 struct Args {
+    /// Scene file to render
+    #[arg(default_value = "assets/worlds/cornell_box.toml")]
+    scene: PathBuf,
     /// Tracer type to use for ray tracing
-    #[arg(short, long, value_enum, default_value_t = TracerType::Embree)]
-    tracer: TracerType,
+    #[arg(short, long, default_value = "embree", value_parser = tracer_parser())]
+    tracer: &'static TracerBackend,
+    /// Render offline and write the image to the output path instead of opening a window
+    #[arg(long)]
+    headless: bool,
+    /// Image written in headless mode. EXR, HDR and PFM outputs keep the linear radiance.
+    /// The viewer saves its snapshots next to it, with a timestamp appended to the name
+    #[arg(short, long, default_value = "render.png")]
+    output: PathBuf,
+    /// Image width [default: window size, or 1280 in headless mode]
+    #[arg(long)]
+    width: Option<u32>,
+    /// Image height [default: window size, or 720 in headless mode]
+    #[arg(long)]
+    height: Option<u32>,
+    /// Samples per pixel [default: 5, or 100 in headless mode]
+    #[arg(long)]
+    samples_per_pixel: Option<u32>,
+    /// Maximum ray depth
+    #[arg(long, default_value_t = 10)]
+    max_depth: u32,
+    /// Time spent rendering each frame in the interactive viewer
+    #[arg(long, default_value_t = 10)]
+    time_budget_ms: u64,
+    /// Tone mapping operator used for display and for saved low dynamic range images
+    #[arg(long, value_enum, default_value_t = ToneMapOperator::Clamp)]
+    tone_mapping: ToneMapOperator,
+    /// Exposure adjustment in stops
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    exposure: f32,
+    /// Extra gamma applied on top of the sRGB transfer function
+    #[arg(long, default_value_t = 1.0)]
+    gamma: f32,
 }
 
-#[derive(clap::ValueEnum, Clone, Copy, Debug)]
-enum TracerType {
-    /// Use the naive ray tracer implementation
-    Naive,
-    /// Use the Embree ray tracer implementation
-    Embree,
+fn tracer_parser() -> impl TypedValueParser<Value = &'static TracerBackend> {
+    PossibleValuesParser::new(
+        tracer::BACKENDS
+            .iter()
+            .map(|backend| PossibleValue::new(backend.name).help(backend.description)),
+    )
+    .map(|name| tracer::backend(&name).expect("only registered backends are accepted"))
 }
 
-fn main() {
+fn main() -> anyhow::Result<()> {
     let args = Args::parse();
 
-    #[derive(serde::Deserialize, serde::Serialize)]
-    struct WorldConfig {
-        camera: CameraSettings,
-        #[serde(flatten)]
-        world: World,
-    }
+    let world = WorldConfig::load(&args.scene)?;
+    let tone_mapping = ToneMapping {
+        operator: args.tone_mapping,
+        exposure: args.exposure,
+        gamma: args.gamma,
+    };
 
-    let world = std::fs::read_to_string("assets/worlds/cornell_box.toml").unwrap();
-    let world: WorldConfig = toml::from_str(&world).unwrap();
+    if args.headless {
+        headless::run(
+            world.world,
+            world.camera,
+            args.tracer,
+            headless::HeadlessSettings {
+                output: &args.output,
+                width: args.width.unwrap_or(1280),
+                height: args.height.unwrap_or(720),
+                samples_per_pixel: args.samples_per_pixel.unwrap_or(100),
+                max_ray_depth: args.max_depth,
+                tone_mapping,
+            },
+        )?;
+    } else {
+        app::run(
+            world.world,
+            world.camera,
+            args.tracer,
+            app::AppSettings {
+                window_size: args.width.zip(args.height),
+                samples_per_pixel: args.samples_per_pixel.unwrap_or(5),
+                max_ray_depth: args.max_depth,
+                time_budget_ms: args.time_budget_ms,
+                tone_mapping,
+                scene_path: args.scene,
+                output: args.output,
+            },
+        );
+    }
 
-    app::run(world.world, world.camera, args.tracer);
+    Ok(())
 }
@@ -1,4 +1,4 @@
-use crate::raytracer::world::Ray;
+use crate::raytracer::world::{Ray, RayDifferentials};
 use glam::{Vec2, Vec3};
 use rand::Rng;
 
@@ -146,6 +146,17 @@ impl Camera {
 
         let ray_direction = (pixel_center - ray_origin).normalize();
 
-        Ray::new(ray_origin, ray_direction)
+        // The neighbouring pixels go through the same point of the lens
+        let differentials = RayDifferentials {
+            x_origin: ray_origin,
+            x_direction: (pixel_center + self.pixel_delta_u - ray_origin).normalize(),
+            y_origin: ray_origin,
+            y_direction: (pixel_center + self.pixel_delta_v - ray_origin).normalize(),
+        };
+
+        Ray {
+            differentials: Some(differentials),
+            ..Ray::new(ray_origin, ray_direction)
+        }
     }
 }
@@ -0,0 +1,141 @@
+use crate::raytracer::tone_mapping::ToneMapping;
+use glam::{Vec3, Vec4};
+use rayon::iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
+use rayon::slice::ParallelSliceMut;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// Accumulated radiance of a single pixel: (r_sum, g_sum, b_sum, sample_count)
+pub type CanvasPixel = (f32, f32, f32, u32);
+
+/// Accumulation buffer shared by the interactive viewer and the headless renderer,
+/// so both produce the same output for the same samples.
+pub struct Canvas {
+    width: u32,
+    height: u32,
+    pixels: Vec<CanvasPixel>,
+}
+
+impl Canvas {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![(0.0, 0.0, 0.0, 0); (width * height) as usize],
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn pixel_count(&self) -> usize {
+        self.pixels.len()
+    }
+
+    pub fn pixels_mut(&mut self) -> &mut [CanvasPixel] {
+        &mut self.pixels
+    }
+
+    pub fn as_mut_ptr(&mut self) -> *mut CanvasPixel {
+        self.pixels.as_mut_ptr()
+    }
+
+    pub fn clear(&mut self) {
+        self.pixels.fill((0.0, 0.0, 0.0, 0));
+    }
+
+    pub fn resize(&mut self, width: u32, height: u32) {
+        self.width = width;
+        self.height = height;
+        self.pixels
+            .resize((width * height) as usize, (0.0, 0.0, 0.0, 0));
+    }
+
+    /// Adds one radiance sample to the pixel.
+    pub fn accumulate(pixel: &mut CanvasPixel, color: Vec4) {
+        pixel.0 += color.x;
+        pixel.1 += color.y;
+        pixel.2 += color.z;
+        pixel.3 += 1;
+    }
+
+    /// The average linear radiance of each pixel. Pixels without samples are black.
+    pub fn to_linear(&self) -> Vec<f32> {
+        let mut values = Vec::with_capacity(self.pixels.len() * 3);
+        for (r_sum, g_sum, b_sum, sample_count) in &self.pixels {
+            if *sample_count > 0 {
+                let count = *sample_count as f32;
+                values.extend_from_slice(&[r_sum / count, g_sum / count, b_sum / count]);
+            } else {
+                values.extend_from_slice(&[0.0, 0.0, 0.0]);
+            }
+        }
+        values
+    }
+
+    /// Tone maps the canvas into sRGB encoded RGBA bytes.
+    pub fn to_bytes(&self, tone_mapping: &ToneMapping) -> Vec<u8> {
+        let mut bytes = vec![255; self.pixels.len() * 4];
+        bytes
+            .par_chunks_mut(4)
+            .zip(self.pixels.par_iter())
+            .for_each(|(bytes, (r_sum, g_sum, b_sum, sample_count))| {
+                if *sample_count > 0 {
+                    let radiance = Vec3::new(*r_sum, *g_sum, *b_sum) / *sample_count as f32;
+                    bytes[..3].copy_from_slice(&tone_mapping.apply_to_bytes(radiance));
+                } else {
+                    bytes[..3].fill(0);
+                }
+            });
+        bytes
+    }
+
+    /// Saves the canvas to `path`. Floating point formats (EXR, HDR, PFM) receive the linear
+    /// radiance, every other format receives the tone mapped values shown in the viewer.
+    pub fn save(&self, path: &Path, tone_mapping: &ToneMapping) -> anyhow::Result<()> {
+        if path
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("pfm"))
+        {
+            return self.save_pfm(path);
+        }
+
+        let is_float_format = matches!(
+            image::ImageFormat::from_path(path),
+            Ok(image::ImageFormat::OpenExr | image::ImageFormat::Hdr)
+        );
+
+        let image = if is_float_format {
+            let buffer = image::Rgb32FImage::from_raw(self.width, self.height, self.to_linear())
+                .expect("canvas size matches its dimensions");
+            image::DynamicImage::ImageRgb32F(buffer)
+        } else {
+            let buffer =
+                image::RgbaImage::from_raw(self.width, self.height, self.to_bytes(tone_mapping))
+                    .expect("canvas size matches its dimensions");
+            image::DynamicImage::ImageRgba8(buffer)
+        };
+
+        image.save(path)?;
+        Ok(())
+    }
+
+    /// Writes the linear radiance as a little endian Portable Float Map, which the image crate
+    /// does not support. PFM stores the rows from bottom to top.
+    fn save_pfm(&self, path: &Path) -> anyhow::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        write!(writer, "PF\n{} {}\n-1.0\n", self.width, self.height)?;
+
+        let linear = self.to_linear();
+        for row in linear.chunks_exact(self.width as usize * 3).rev() {
+            for value in row {
+                writer.write_all(&value.to_le_bytes())?;
+            }
+        }
+
+        writer.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,316 @@
+use crate::raytracer::light::{cumulative, sample_cdf};
+use crate::raytracer::loader::EnvironmentMapSettings;
+use glam::{Mat3, Vec2, Vec3, Vec4};
+use serde::{Deserialize, Serialize};
+use std::f32::consts::PI;
+use std::sync::Arc;
+
+/// Radiance arriving along rays that leave the scene without hitting anything.
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub enum Environment {
+    #[default]
+    None,
+    Constant {
+        color: Vec4,
+    },
+    /// Blends from `bottom` when looking straight down to `top` when looking straight up.
+    Gradient {
+        #[serde(default = "default_gradient_bottom")]
+        bottom: Vec4,
+        #[serde(default = "default_gradient_top")]
+        top: Vec4,
+    },
+    #[serde(
+        deserialize_with = "crate::raytracer::loader::deserialize_environment_map",
+        serialize_with = "crate::raytracer::loader::serialize_environment_map"
+    )]
+    Map(EnvironmentMap),
+}
+
+fn default_gradient_bottom() -> Vec4 {
+    Vec4::ONE
+}
+
+fn default_gradient_top() -> Vec4 {
+    Vec4::new(0.5, 0.7, 1.0, 1.0)
+}
+
+/// A direction sampled towards the environment.
+pub struct EnvironmentSample {
+    pub direction: Vec3,
+    pub radiance: Vec4,
+    /// Solid angle probability density of the direction.
+    pub pdf: f32,
+}
+
+impl Environment {
+    pub fn radiance(&self, direction: Vec3) -> Vec4 {
+        match self {
+            Environment::None => Vec4::ZERO,
+            Environment::Constant { color } => *color,
+            Environment::Gradient { bottom, top } => {
+                let t = 0.5 * (direction.normalize().y + 1.0);
+                bottom * (1.0 - t) + top * t
+            }
+            Environment::Map(map) => map.radiance(direction),
+        }
+    }
+
+    /// Radiance averaged over every direction, used to weigh the environment against
+    /// the other lights.
+    pub fn mean_radiance(&self) -> Vec4 {
+        match self {
+            Environment::None => Vec4::ZERO,
+            Environment::Constant { color } => *color,
+            // Uniformly distributed directions have a uniformly distributed height
+            Environment::Gradient { bottom, top } => (bottom + top) * 0.5,
+            Environment::Map(map) => map.data.mean_radiance * map.settings.intensity,
+        }
+    }
+
+    /// Samples a direction proportionally to the radiance of maps, or uniformly otherwise.
+    pub fn sample(&self) -> Option<EnvironmentSample> {
+        match self {
+            Environment::None => None,
+            Environment::Constant { .. } | Environment::Gradient { .. } => {
+                let cos_theta = 1.0 - 2.0 * rand::random::<f32>();
+                let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+                let phi = 2.0 * PI * rand::random::<f32>();
+                let direction = Vec3::new(sin_theta * phi.cos(), cos_theta, sin_theta * phi.sin());
+
+                Some(EnvironmentSample {
+                    direction,
+                    radiance: self.radiance(direction),
+                    pdf: 1.0 / (4.0 * PI),
+                })
+            }
+            Environment::Map(map) => map.sample(),
+        }
+    }
+
+    /// Solid angle density with which [`Environment::sample`] generates `direction`.
+    pub fn pdf(&self, direction: Vec3) -> f32 {
+        match self {
+            Environment::None => 0.0,
+            Environment::Constant { .. } | Environment::Gradient { .. } => 1.0 / (4.0 * PI),
+            Environment::Map(map) => map.pdf(direction),
+        }
+    }
+}
+
+/// Equirectangular image with +Y up, importance sampled by the luminance of its pixels.
+#[derive(Clone)]
+pub struct EnvironmentMap {
+    pub settings: EnvironmentMapSettings,
+    /// Shared so cloning the world doesn't copy the image.
+    data: Arc<EnvironmentMapData>,
+}
+
+struct EnvironmentMapData {
+    image: image::Rgb32FImage,
+    /// Rotations from world to map directions and back.
+    to_map: Mat3,
+    to_world: Mat3,
+    mean_radiance: Vec4,
+    /// Cumulative probability of picking each row.
+    row_cdf: Vec<f32>,
+    /// Cumulative probability of each pixel inside its row, row after row.
+    column_cdfs: Vec<f32>,
+    /// Probability of picking each pixel.
+    pixel_probabilities: Vec<f32>,
+}
+
+impl EnvironmentMap {
+    pub fn new(settings: EnvironmentMapSettings, image: image::Rgb32FImage) -> Self {
+        let (width, height) = image.dimensions();
+
+        // Weigh each pixel by its luminance and the solid angle it covers, which shrinks
+        // towards the poles
+        let mut weights = Vec::with_capacity((width * height) as usize);
+        let mut total_radiance = Vec3::ZERO;
+        let mut total_solid_angle = 0.0;
+        for y in 0..height {
+            let sin_theta = (PI * (y as f32 + 0.5) / height as f32).sin();
+            for x in 0..width {
+                let color = Vec3::from(image.get_pixel(x, y).0).max(Vec3::ZERO);
+                weights.push(luminance(color) * sin_theta);
+                total_radiance += color * sin_theta;
+                total_solid_angle += sin_theta;
+            }
+        }
+
+        let row_weights: Vec<f32> = weights
+            .chunks_exact(width as usize)
+            .map(|row| row.iter().sum())
+            .collect();
+        let row_cdf = cumulative(&row_weights);
+        let column_cdfs = weights
+            .chunks_exact(width as usize)
+            .flat_map(cumulative)
+            .collect();
+
+        let total_weight: f32 = weights.iter().sum();
+        let pixel_probabilities = if total_weight > 0.0 {
+            weights.iter().map(|weight| weight / total_weight).collect()
+        } else {
+            vec![1.0 / weights.len() as f32; weights.len()]
+        };
+
+        let rotation = settings.rotation.to_radians();
+        let data = EnvironmentMapData {
+            image,
+            to_map: Mat3::from_rotation_y(-rotation),
+            to_world: Mat3::from_rotation_y(rotation),
+            mean_radiance: (total_radiance / total_solid_angle).extend(1.0),
+            row_cdf,
+            column_cdfs,
+            pixel_probabilities,
+        };
+
+        Self {
+            settings,
+            data: Arc::new(data),
+        }
+    }
+
+    fn radiance(&self, direction: Vec3) -> Vec4 {
+        let (u, v) = direction_to_uv(self.data.to_map * direction.normalize());
+        let (x, y) = self.pixel_at(u, v);
+        self.pixel_radiance(x, y)
+    }
+
+    fn sample(&self) -> Option<EnvironmentSample> {
+        let (width, height) = self.data.image.dimensions();
+
+        let y = sample_cdf(&self.data.row_cdf);
+        let row = y * width as usize..(y + 1) * width as usize;
+        let x = sample_cdf(&self.data.column_cdfs[row]);
+
+        let u = (x as f32 + rand::random::<f32>()) / width as f32;
+        let v = (y as f32 + rand::random::<f32>()) / height as f32;
+        let pdf = self.uv_pdf(x as u32, y as u32, v)?;
+
+        Some(EnvironmentSample {
+            direction: self.data.to_world * uv_to_direction(u, v),
+            radiance: self.pixel_radiance(x as u32, y as u32),
+            pdf,
+        })
+    }
+
+    fn pdf(&self, direction: Vec3) -> f32 {
+        let (u, v) = direction_to_uv(self.data.to_map * direction.normalize());
+        let (x, y) = self.pixel_at(u, v);
+        self.uv_pdf(x, y, v).unwrap_or(0.0)
+    }
+
+    /// Converts the probability of the pixel into a solid angle density, using the
+    /// Jacobian of the equirectangular mapping.
+    fn uv_pdf(&self, x: u32, y: u32, v: f32) -> Option<f32> {
+        let (width, height) = self.data.image.dimensions();
+        let sin_theta = (PI * v).sin();
+        if sin_theta <= 0.0 {
+            return None;
+        }
+
+        let probability = self.data.pixel_probabilities[(y * width + x) as usize];
+        Some(probability * (width * height) as f32 / (2.0 * PI * PI * sin_theta))
+    }
+
+    fn pixel_at(&self, u: f32, v: f32) -> (u32, u32) {
+        let (width, height) = self.data.image.dimensions();
+        let x = ((u * width as f32) as u32).min(width - 1);
+        let y = ((v * height as f32) as u32).min(height - 1);
+        (x, y)
+    }
+
+    fn pixel_radiance(&self, x: u32, y: u32) -> Vec4 {
+        let color = Vec3::from(self.data.image.get_pixel(x, y).0).max(Vec3::ZERO);
+        (color * self.settings.intensity).extend(1.0)
+    }
+}
+
+/// Maps a normalized direction to equirectangular coordinates, with v = 0 straight up.
+fn direction_to_uv(direction: Vec3) -> (f32, f32) {
+    let phi = direction.z.atan2(direction.x);
+    // Unlike acos, stays precise close to the poles, where the density changes fastest
+    let theta = Vec2::new(direction.x, direction.z)
+        .length()
+        .atan2(direction.y);
+    ((phi + PI) / (2.0 * PI), theta / PI)
+}
+
+fn uv_to_direction(u: f32, v: f32) -> Vec3 {
+    let phi = u * 2.0 * PI - PI;
+    let theta = v * PI;
+    Vec3::new(
+        theta.sin() * phi.cos(),
+        theta.cos(),
+        theta.sin() * phi.sin(),
+    )
+}
+
+fn luminance(color: Vec3) -> f32 {
+    color.dot(Vec3::new(0.2126, 0.7152, 0.0722))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EnvironmentMap, uv_to_direction};
+    use crate::raytracer::loader::EnvironmentMapSettings;
+    use std::f32::consts::PI;
+    use std::path::PathBuf;
+
+    /// An 8 × 4 map, rotated so the samples go through the rotations, with a black row, a bright
+    /// pixel and a gradient elsewhere.
+    fn map() -> EnvironmentMap {
+        let image = image::Rgb32FImage::from_fn(8, 4, |x, y| match (x, y) {
+            (_, 0) => image::Rgb([0.0; 3]),
+            (5, 2) => image::Rgb([20.0, 10.0, 5.0]),
+            _ => image::Rgb([x as f32 * 0.1, y as f32 * 0.2, 0.5]),
+        });
+        let settings = EnvironmentMapSettings {
+            path: PathBuf::new(),
+            rotation: 30.0,
+            intensity: 1.0,
+        };
+        EnvironmentMap::new(settings, image)
+    }
+
+    #[test]
+    fn pdf_integrates_to_one() {
+        let map = map();
+        let (rows, columns) = (400, 800);
+        let mut integral = 0.0_f64;
+        for row in 0..rows {
+            let v = (row as f32 + 0.5) / rows as f32;
+            let solid_angle = (PI * v).sin() * (PI / rows as f32) * (2.0 * PI / columns as f32);
+            for column in 0..columns {
+                let u = (column as f32 + 0.5) / columns as f32;
+                integral += f64::from(map.pdf(uv_to_direction(u, v)) * solid_angle);
+            }
+        }
+        assert!(
+            (integral - 1.0).abs() < 1e-3,
+            "the pdf integrates to {integral}"
+        );
+    }
+
+    #[test]
+    fn samples_match_pdf() {
+        let map = map();
+        for _ in 0..10_000 {
+            let sample = map.sample().unwrap();
+            let pdf = map.pdf(sample.direction);
+            assert!(
+                (sample.pdf - pdf).abs() <= 1e-3 * pdf,
+                "sampled {} with pdf {} but its pdf is {pdf}",
+                sample.direction,
+                sample.pdf
+            );
+            assert_eq!(sample.radiance, map.radiance(sample.direction));
+            // Nothing is sampled where the map is black
+            assert!(sample.radiance.truncate().max_element() > 0.0);
+        }
+    }
+}
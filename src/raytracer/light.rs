@@ -0,0 +1,465 @@
+use crate::raytracer::environment::Environment;
+use crate::raytracer::tracer::TraceResult;
+use crate::raytracer::world::{Aabb, Geometry, GeometryType, Transform};
+use glam::{Vec3, Vec4};
+use std::f32::consts::PI;
+
+/// A point sampled on a light, as seen from the point being shaded.
+pub struct LightSample {
+    /// Normalized direction from the shaded point towards the light.
+    pub direction: Vec3,
+    /// Distance to the sampled point, infinite for the environment.
+    pub distance: f32,
+    pub emission: Vec4,
+    /// Geometry whose emission varies over its surface, so `emission` only scales what it
+    /// emits where the sample lands.
+    pub textured_emitter: Option<usize>,
+    /// Solid angle probability density of the sample, including the light selection.
+    pub pdf: f32,
+}
+
+/// Every emissive geometry of the world and the environment, used for next-event estimation.
+pub struct LightList {
+    lights: Vec<Light>,
+    /// Cumulative selection probability of each light, proportional to its power.
+    cdf: Vec<f32>,
+    /// Index into `lights` for each geometry index, if the geometry emits light.
+    geometry_lights: Vec<Option<usize>>,
+    /// Index into `lights` of the environment, if it emits light.
+    environment_light: Option<usize>,
+}
+
+struct Light {
+    /// Emitted radiance, or its average when the emission varies over the surface.
+    emission: Vec4,
+    /// Index of the geometry when its emission varies over the surface.
+    textured_emitter: Option<usize>,
+    /// Whether the back of the shape emits too, otherwise only the side its normals face does.
+    two_sided: bool,
+    shape: LightShape,
+    selection_pdf: f32,
+}
+
+enum LightShape {
+    /// Spheres are sampled by the cone they subtend from the shaded point.
+    Sphere { center: Vec3, radius: f32 },
+    /// Flat patches are picked proportionally to their area and sampled uniformly.
+    Patches {
+        patches: Vec<Patch>,
+        cdf: Vec<f32>,
+        area: f32,
+    },
+    /// The environment samples its own directions, and provides the emission along them.
+    Environment(Environment),
+}
+
+/// Parallelogram (or triangle) spanned by `u` and `v` from `origin`, facing `u × v`.
+struct Patch {
+    origin: Vec3,
+    u: Vec3,
+    v: Vec3,
+    triangle: bool,
+}
+
+impl Patch {
+    fn parallelogram(origin: Vec3, u: Vec3, v: Vec3) -> Self {
+        Self {
+            origin,
+            u,
+            v,
+            triangle: false,
+        }
+    }
+
+    fn triangle(p1: Vec3, p2: Vec3, p3: Vec3) -> Self {
+        Self {
+            origin: p1,
+            u: p2 - p1,
+            v: p3 - p1,
+            triangle: true,
+        }
+    }
+
+    /// The same patch facing away from `center`.
+    fn facing_away(self, center: Vec3) -> Self {
+        let patch_center = self.origin + 0.5 * (self.u + self.v);
+        if self.u.cross(self.v).dot(patch_center - center) < 0.0 {
+            Self {
+                u: self.v,
+                v: self.u,
+                ..self
+            }
+        } else {
+            self
+        }
+    }
+
+    fn area(&self) -> f32 {
+        let area = self.u.cross(self.v).length();
+        if self.triangle { area * 0.5 } else { area }
+    }
+
+    fn sample(&self) -> (Vec3, Vec3) {
+        let (mut a, mut b): (f32, f32) = (rand::random(), rand::random());
+        if self.triangle && a + b > 1.0 {
+            a = 1.0 - a;
+            b = 1.0 - b;
+        }
+        let point = self.origin + a * self.u + b * self.v;
+        let normal = self.u.cross(self.v).normalize();
+        (point, normal)
+    }
+}
+
+impl LightList {
+    pub fn new(geometry: &[Geometry], environment: &Environment) -> Self {
+        let mut lights = Vec::new();
+        let mut powers = Vec::new();
+        let mut geometry_lights = vec![None; geometry.len()];
+
+        for (index, geom) in geometry.iter().enumerate() {
+            let Some(emission) = geom.material.average_emission() else {
+                continue;
+            };
+            if emission.truncate().max_element() <= 0.0 {
+                continue;
+            }
+
+            let shape = match &geom.geometry_type {
+                GeometryType::Sphere { center, radius } => LightShape::Sphere {
+                    center: *center,
+                    radius: *radius,
+                },
+                GeometryType::Quad { origin, u, v } => {
+                    LightShape::patches(vec![Patch::parallelogram(*origin, *u, *v)])
+                }
+                GeometryType::TriangleMesh(mesh) => LightShape::patches(
+                    mesh.indices
+                        .iter()
+                        .map(|&(v1, v2, v3)| {
+                            Patch::triangle(
+                                mesh.verts[v1 as usize].into(),
+                                mesh.verts[v2 as usize].into(),
+                                mesh.verts[v3 as usize].into(),
+                            )
+                        })
+                        .collect(),
+                ),
+                GeometryType::Box { origin, u, v, w } => {
+                    let (origin, u, v, w) = (*origin, *u, *v, *w);
+                    let center = origin + 0.5 * (u + v + w);
+                    LightShape::patches(
+                        [
+                            Patch::parallelogram(origin, u, v),
+                            Patch::parallelogram(origin + w, u, v),
+                            Patch::parallelogram(origin, v, w),
+                            Patch::parallelogram(origin + u, v, w),
+                            Patch::parallelogram(origin, u, w),
+                            Patch::parallelogram(origin + v, u, w),
+                        ]
+                        .map(|patch| patch.facing_away(center))
+                        .into(),
+                    )
+                }
+            };
+
+            // Spheres can't be sampled as lights once they are stretched into ellipsoids,
+            // they are still found by scattering
+            let Some(shape) = shape.transformed(&geom.transform) else {
+                continue;
+            };
+            if shape.area() <= 0.0 {
+                continue;
+            }
+
+            geometry_lights[index] = Some(lights.len());
+            powers.push(shape.area() * emission.truncate().element_sum() / 3.0);
+            lights.push(Light {
+                emission,
+                textured_emitter: geom.material.uniform_emission().is_none().then_some(index),
+                two_sided: geom.material.two_sided_emission(),
+                shape,
+                selection_pdf: 0.0,
+            });
+        }
+
+        let mut environment_light = None;
+        let environment_radiance = environment.mean_radiance().truncate().element_sum() / 3.0;
+        if environment_radiance > 0.0 {
+            // The environment lights the scene as if it were a sphere around it
+            let bounds = geometry
+                .iter()
+                .map(Geometry::bounds)
+                .fold(Aabb::EMPTY, Aabb::union);
+            let radius = if bounds == Aabb::EMPTY {
+                1.0
+            } else {
+                (0.5 * (bounds.max - bounds.min).length()).max(1.0)
+            };
+
+            environment_light = Some(lights.len());
+            powers.push(4.0 * PI * radius * radius * environment_radiance);
+            lights.push(Light {
+                emission: Vec4::ONE,
+                textured_emitter: None,
+                two_sided: true,
+                shape: LightShape::Environment(environment.clone()),
+                selection_pdf: 0.0,
+            });
+        }
+
+        let total_power: f32 = powers.iter().sum();
+
+        for (light, power) in lights.iter_mut().zip(&powers) {
+            light.selection_pdf = power / total_power;
+        }
+        let cdf = cumulative(&powers);
+
+        Self {
+            lights,
+            cdf,
+            geometry_lights,
+            environment_light,
+        }
+    }
+
+    /// Picks a light proportionally to its power and samples a direction towards it.
+    pub fn sample(&self, point: Vec3) -> Option<LightSample> {
+        if self.lights.is_empty() {
+            return None;
+        }
+
+        let light = &self.lights[sample_cdf(&self.cdf)];
+        let mut sample = light.shape.sample(point, light.two_sided)?;
+        match light.textured_emitter {
+            Some(index) => sample.textured_emitter = Some(index),
+            None => sample.emission *= light.emission,
+        }
+        sample.pdf *= light.selection_pdf;
+        Some(sample)
+    }
+
+    /// Solid angle density with which [`LightList::sample`] would have generated the
+    /// direction from `origin` to the hit, or zero if the hit geometry is not a light.
+    pub fn pdf(&self, origin: Vec3, hit: &TraceResult) -> f32 {
+        let Some(light_index) = self.geometry_lights[hit.geometry_index] else {
+            return 0.0;
+        };
+        let light = &self.lights[light_index];
+
+        light.selection_pdf * light.shape.pdf(origin, hit)
+    }
+
+    /// Solid angle density with which [`LightList::sample`] would have generated `direction`
+    /// towards the environment.
+    pub fn environment_pdf(&self, direction: Vec3) -> f32 {
+        let Some(light_index) = self.environment_light else {
+            return 0.0;
+        };
+        let light = &self.lights[light_index];
+        let LightShape::Environment(environment) = &light.shape else {
+            unreachable!("the environment light always has the environment shape");
+        };
+
+        light.selection_pdf * environment.pdf(direction)
+    }
+}
+
+impl LightShape {
+    fn patches(patches: Vec<Patch>) -> Self {
+        let areas: Vec<f32> = patches.iter().map(Patch::area).collect();
+        let area = areas.iter().sum();
+        let cdf = cumulative(&areas);
+        LightShape::Patches { patches, cdf, area }
+    }
+
+    /// Moves the shape into world space, if it can still be sampled afterwards.
+    fn transformed(self, transform: &Transform) -> Option<Self> {
+        if transform.is_identity() {
+            return Some(self);
+        }
+
+        match self {
+            LightShape::Sphere { center, radius } => Some(LightShape::Sphere {
+                center: transform.point_to_world(center),
+                radius: radius * transform.uniform_scale()?,
+            }),
+            LightShape::Patches { patches, .. } => {
+                // Mirroring flips the cross product, not the side the surface faces
+                let mirrored = transform.object_to_world.matrix3.determinant() < 0.0;
+                Some(LightShape::patches(
+                    patches
+                        .into_iter()
+                        .map(|patch| {
+                            let (u, v) = if mirrored {
+                                (patch.v, patch.u)
+                            } else {
+                                (patch.u, patch.v)
+                            };
+                            Patch {
+                                origin: transform.point_to_world(patch.origin),
+                                u: transform.vector_to_world(u),
+                                v: transform.vector_to_world(v),
+                                triangle: patch.triangle,
+                            }
+                        })
+                        .collect(),
+                ))
+            }
+            LightShape::Environment(_) => Some(self),
+        }
+    }
+
+    fn area(&self) -> f32 {
+        match self {
+            LightShape::Sphere { radius, .. } => 4.0 * PI * radius * radius,
+            LightShape::Patches { area, .. } => *area,
+            LightShape::Environment(_) => f32::INFINITY,
+        }
+    }
+
+    /// Samples a direction from `point` towards the shape. One-sided shapes return `None` when
+    /// the sampled point faces away, and spheres are always seen from their emitting outside.
+    fn sample(&self, point: Vec3, two_sided: bool) -> Option<LightSample> {
+        match self {
+            LightShape::Sphere { center, radius } => {
+                let to_center = *center - point;
+                let distance_squared = to_center.length_squared();
+                if distance_squared <= radius * radius {
+                    return None;
+                }
+
+                let distance_to_center = distance_squared.sqrt();
+                let sin_theta_max_squared = radius * radius / distance_squared;
+                let cos_theta_max = (1.0 - sin_theta_max_squared).max(0.0).sqrt();
+
+                let cos_theta = 1.0 - rand::random::<f32>() * (1.0 - cos_theta_max);
+                let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+                let phi = 2.0 * PI * rand::random::<f32>();
+
+                let w = to_center / distance_to_center;
+                let (u, v) = w.any_orthonormal_pair();
+                let direction =
+                    (u * phi.cos() * sin_theta + v * phi.sin() * sin_theta + w * cos_theta)
+                        .normalize();
+
+                // Distance to the near side of the sphere along the sampled direction
+                let projection = distance_to_center * cos_theta;
+                let distance = projection
+                    - (radius * radius - distance_squared * sin_theta * sin_theta)
+                        .max(0.0)
+                        .sqrt();
+
+                Some(LightSample {
+                    direction,
+                    distance,
+                    emission: Vec4::ONE,
+                    textured_emitter: None,
+                    pdf: cone_pdf(cos_theta_max),
+                })
+            }
+            LightShape::Patches {
+                patches, cdf, area, ..
+            } => {
+                let (light_point, normal) = patches[sample_cdf(cdf)].sample();
+                let to_light = light_point - point;
+                let distance_squared = to_light.length_squared();
+                let distance = distance_squared.sqrt();
+                let direction = to_light / distance;
+
+                let cos_light = -normal.dot(direction);
+                if !two_sided && cos_light <= 0.0 {
+                    return None;
+                }
+                let cos_light = cos_light.abs();
+                if cos_light < 1e-6 {
+                    return None;
+                }
+
+                Some(LightSample {
+                    direction,
+                    distance,
+                    emission: Vec4::ONE,
+                    textured_emitter: None,
+                    pdf: distance_squared / (cos_light * area),
+                })
+            }
+            LightShape::Environment(environment) => {
+                let sample = environment.sample()?;
+                Some(LightSample {
+                    direction: sample.direction,
+                    distance: f32::INFINITY,
+                    emission: sample.radiance,
+                    textured_emitter: None,
+                    pdf: sample.pdf,
+                })
+            }
+        }
+    }
+
+    fn pdf(&self, origin: Vec3, hit: &TraceResult) -> f32 {
+        match self {
+            LightShape::Sphere { center, radius } => {
+                let distance_squared = (*center - origin).length_squared();
+                if distance_squared <= radius * radius {
+                    return 0.0;
+                }
+                let sin_theta_max_squared = radius * radius / distance_squared;
+                cone_pdf((1.0 - sin_theta_max_squared).max(0.0).sqrt())
+            }
+            LightShape::Patches { area, .. } => {
+                let to_light = hit.point - origin;
+                let distance_squared = to_light.length_squared();
+                let cos_light = hit.normal.dot(to_light.normalize()).abs();
+                if cos_light < 1e-6 {
+                    return 0.0;
+                }
+                distance_squared / (cos_light * area)
+            }
+            // Rays reaching the environment don't hit anything
+            LightShape::Environment(_) => 0.0,
+        }
+    }
+}
+
+/// Uniform density over the cone of directions with the given half-angle cosine.
+fn cone_pdf(cos_theta_max: f32) -> f32 {
+    1.0 / (2.0 * PI * (1.0 - cos_theta_max).max(1e-7))
+}
+
+/// Cumulative distribution of the weights, uniform if they are all zero.
+pub(super) fn cumulative(weights: &[f32]) -> Vec<f32> {
+    let total: f32 = weights.iter().sum();
+    if total <= 0.0 {
+        let count = weights.len() as f32;
+        return (1..=weights.len())
+            .map(|index| index as f32 / count)
+            .collect();
+    }
+
+    let mut sum = 0.0;
+    weights
+        .iter()
+        .map(|weight| {
+            sum += weight / total;
+            sum
+        })
+        .collect()
+}
+
+pub(super) fn sample_cdf(cdf: &[f32]) -> usize {
+    let random: f32 = rand::random();
+    cdf.partition_point(|&value| value < random)
+        .min(cdf.len() - 1)
+}
+
+/// Power heuristic (β = 2) weight for combining two sampling strategies.
+pub fn power_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+    let pdf = pdf * pdf;
+    let other_pdf = other_pdf * other_pdf;
+    if pdf + other_pdf == 0.0 {
+        0.0
+    } else {
+        pdf / (pdf + other_pdf)
+    }
+}
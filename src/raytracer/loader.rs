@@ -1,20 +1,256 @@
-use std::path::PathBuf;
+mod gltf;
+mod obj;
+mod ply;
+mod stl;
 
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::Context;
+use glam::{Affine3A, Mat4, Quat, Vec3};
 use serde::{Deserialize, Serialize};
 
-use crate::raytracer::{camera::Camera, world::TriangleMeshGeometry};
+use crate::raytracer::{
+    camera::Camera,
+    environment::{Environment, EnvironmentMap},
+    material::{
+        MaterialType,
+        texture::{ColorSpace, ImageTexture, Sampler, Texture},
+    },
+    medium::Medium,
+    world::{Geometry, GeometryType, Transform, TriangleMeshGeometry, World},
+};
+
+#[derive(Serialize, Deserialize)]
+#[serde(try_from = "WorldConfigSettings")]
+pub struct WorldConfig {
+    pub camera: CameraSettings,
+    #[serde(flatten)]
+    pub world: World,
+}
+
+/// Scene file layout of [`WorldConfig`], where the camera can come from an imported scene.
+#[derive(Deserialize)]
+struct WorldConfigSettings {
+    camera: Option<CameraSettings>,
+    #[serde(flatten)]
+    world: World,
+}
+
+impl TryFrom<WorldConfigSettings> for WorldConfig {
+    type Error = &'static str;
+
+    fn try_from(settings: WorldConfigSettings) -> Result<Self, Self::Error> {
+        let camera = settings
+            .camera
+            .or_else(|| settings.world.imported_camera.clone())
+            .ok_or("the scene has no [camera] and no imported scene has one")?;
+        Ok(Self {
+            camera,
+            world: settings.world,
+        })
+    }
+}
+
+impl WorldConfig {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read scene file {}", path.display()))?;
+        toml::from_str(&contents)
+            .with_context(|| format!("failed to parse scene file {}", path.display()))
+    }
+}
+
+/// Scene file layout of [`World`], before meshes are loaded and instances resolved.
+#[derive(Deserialize)]
+pub struct WorldSettings {
+    /// Meshes loaded once and placed any number of times by `instance` geometries.
+    #[serde(default)]
+    meshes: BTreeMap<String, TriangleMeshGeometrySettings>,
+    geometry: Vec<toml::Value>,
+    #[serde(default)]
+    environment: Environment,
+    fog: Option<Medium>,
+}
+
+/// A placement of a mesh from the `meshes` table.
+#[derive(Deserialize)]
+struct InstanceSettings {
+    mesh: String,
+    #[serde(flatten)]
+    material: MaterialType,
+    medium: Option<Medium>,
+    #[serde(default)]
+    transform: Transform,
+}
+
+impl TryFrom<WorldSettings> for World {
+    type Error = String;
+
+    fn try_from(settings: WorldSettings) -> Result<Self, Self::Error> {
+        let meshes = settings
+            .meshes
+            .into_iter()
+            .map(|(name, mesh)| {
+                let mesh: TriangleMeshGeometry = mesh
+                    .try_into()
+                    .map_err(|err: anyhow::Error| format!("mesh {name}: {err:#}"))?;
+                Ok((name, Arc::new(mesh)))
+            })
+            .collect::<Result<BTreeMap<_, _>, String>>()?;
+
+        // Each geometry is deserialized on its own so errors point at the offending entry
+        let mut geometry = Vec::new();
+        let mut imported_camera = None;
+        for (index, value) in settings.geometry.into_iter().enumerate() {
+            let geometry_type = value
+                .get("type")
+                .and_then(toml::Value::as_str)
+                .unwrap_or("unknown")
+                .to_owned();
+            let error = |message: &str| format!("geometry #{index} ({geometry_type}): {message}");
+
+            match geometry_type.as_str() {
+                "instance" => {
+                    let instance: InstanceSettings = value
+                        .try_into()
+                        .map_err(|err: toml::de::Error| error(err.message()))?;
+                    let mesh = meshes
+                        .get(&instance.mesh)
+                        .ok_or_else(|| error(&format!("unknown mesh {}", instance.mesh)))?;
+                    geometry.push(Geometry {
+                        geometry_type: GeometryType::TriangleMesh(mesh.clone()),
+                        material: instance.material,
+                        medium: instance.medium,
+                        transform: instance.transform,
+                    });
+                }
+                "gltf_scene" => {
+                    let scene: gltf::GltfSceneSettings = value
+                        .try_into()
+                        .map_err(|err: toml::de::Error| error(err.message()))?;
+                    let scene = scene.load().map_err(|err| error(&format!("{err:#}")))?;
+                    geometry.extend(scene.geometry);
+                    imported_camera = imported_camera.or(scene.camera);
+                }
+                "obj_scene" => {
+                    let scene: obj::ObjSceneSettings = value
+                        .try_into()
+                        .map_err(|err: toml::de::Error| error(err.message()))?;
+                    geometry.extend(scene.load().map_err(|err| error(&format!("{err:#}")))?);
+                }
+                _ => geometry.push(
+                    value
+                        .try_into()
+                        .map_err(|err: toml::de::Error| error(err.message()))?,
+                ),
+            }
+        }
+
+        Ok(World {
+            geometry,
+            environment: settings.environment,
+            fog: settings.fog,
+            imported_camera,
+        })
+    }
+}
+
+/// Scene file layout of [`Transform`]. Either a matrix or any combination of scale, rotation
+/// and translation, applied in that order.
+#[derive(Default, Serialize, Deserialize)]
+pub struct TransformSettings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    translate: Option<Vec3>,
+    /// Rotation in degrees around the X axis, then Y, then Z.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rotate: Option<Vec3>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    scale: Option<ScaleSettings>,
+    /// Row major affine matrix, the last row must be [0, 0, 0, 1].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    matrix: Option<[[f32; 4]; 4]>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum ScaleSettings {
+    Uniform(f32),
+    PerAxis(Vec3),
+}
+
+impl TryFrom<TransformSettings> for Transform {
+    type Error = String;
+
+    fn try_from(settings: TransformSettings) -> Result<Self, Self::Error> {
+        let has_components =
+            settings.translate.is_some() || settings.rotate.is_some() || settings.scale.is_some();
+
+        let object_to_world = match settings.matrix {
+            Some(_) if has_components => {
+                return Err("matrix can't be combined with translate, rotate or scale".into());
+            }
+            Some(rows) => {
+                if rows[3] != [0.0, 0.0, 0.0, 1.0] {
+                    return Err("the last row of the matrix must be [0, 0, 0, 1]".into());
+                }
+                Affine3A::from_mat4(Mat4::from_cols_array_2d(&rows).transpose())
+            }
+            None => {
+                let scale = match settings.scale {
+                    Some(ScaleSettings::Uniform(scale)) => Vec3::splat(scale),
+                    Some(ScaleSettings::PerAxis(scale)) => scale,
+                    None => Vec3::ONE,
+                };
+                let rotate = settings.rotate.unwrap_or_default();
+                let rotation = Quat::from_rotation_z(rotate.z.to_radians())
+                    * Quat::from_rotation_y(rotate.y.to_radians())
+                    * Quat::from_rotation_x(rotate.x.to_radians());
+                let translation = settings.translate.unwrap_or_default();
+                Affine3A::from_scale_rotation_translation(scale, rotation, translation)
+            }
+        };
+
+        if object_to_world.matrix3.determinant().abs() < 1e-12 {
+            return Err("the transform is not invertible".into());
+        }
+        Ok(Transform::new(object_to_world))
+    }
+}
+
+impl From<Transform> for TransformSettings {
+    fn from(transform: Transform) -> Self {
+        if transform.is_identity() {
+            return Self::default();
+        }
+        Self {
+            matrix: Some(
+                Mat4::from(transform.object_to_world)
+                    .transpose()
+                    .to_cols_array_2d(),
+            ),
+            ..Self::default()
+        }
+    }
+}
 
-pub fn deserialize_triangle_mesh<'de, D>(deserializer: D) -> Result<TriangleMeshGeometry, D::Error>
+pub fn deserialize_triangle_mesh<'de, D>(
+    deserializer: D,
+) -> Result<Arc<TriangleMeshGeometry>, D::Error>
 where
     D: serde::Deserializer<'de>,
 {
     let settings = TriangleMeshGeometrySettings::deserialize(deserializer)?;
-    settings.try_into().map_err(serde::de::Error::custom)
+    settings
+        .try_into()
+        .map(Arc::new)
+        .map_err(|err: anyhow::Error| serde::de::Error::custom(format!("{err:#}")))
 }
 
 /// Only for debug purposes
 pub fn serialize_triangle_mesh<S>(
-    _mesh: &TriangleMeshGeometry,
+    _mesh: &Arc<TriangleMeshGeometry>,
     serializer: S,
 ) -> Result<S::Ok, S::Error>
 where
@@ -24,23 +260,81 @@ where
     serializer.serialize_str(path)
 }
 
-pub fn deserialize_image<'de, D>(deserializer: D) -> Result<image::Rgba32FImage, D::Error>
+/// Image texture as written in scene files.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageTextureSettings {
+    pub image: PathBuf,
+    /// Encoding of the image file, decoded to linear values when loading it.
+    #[serde(default)]
+    pub color_space: ColorSpace,
+    #[serde(flatten)]
+    pub sampler: Sampler,
+}
+
+pub fn deserialize_image_texture<'de, D>(deserializer: D) -> Result<ImageTexture, D::Error>
 where
     D: serde::Deserializer<'de>,
 {
-    let path: PathBuf = Deserialize::deserialize(deserializer)?;
-    Ok(image::open(path)
-        .map_err(serde::de::Error::custom)?
-        .into_rgba32f())
+    let settings = ImageTextureSettings::deserialize(deserializer)?;
+    let mut image = image::open(&settings.image)
+        .map_err(|err| {
+            serde::de::Error::custom(format!(
+                "failed to load image {}: {err}",
+                settings.image.display()
+            ))
+        })?
+        .into_rgba32f();
+    settings.color_space.decode(&mut image);
+    Ok(ImageTexture::new(image, settings.sampler))
 }
 
 /// Only for debug purposes
-pub fn serialize_image<S>(_image: &image::Rgba32FImage, serializer: S) -> Result<S::Ok, S::Error>
+pub fn serialize_image_texture<S>(texture: &ImageTexture, serializer: S) -> Result<S::Ok, S::Error>
 where
     S: serde::Serializer,
 {
-    let path = "temp_image.png"; // Temporary path for serialization
-    serializer.serialize_str(path)
+    ImageTextureSettings {
+        image: PathBuf::from("temp_image.png"), // Temporary path for serialization
+        color_space: ColorSpace::Linear,
+        sampler: texture.sampler().clone(),
+    }
+    .serialize(serializer)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EnvironmentMapSettings {
+    /// Equirectangular image, usually HDR or EXR.
+    pub path: PathBuf,
+    /// Rotation around the vertical axis, in degrees.
+    #[serde(default)]
+    pub rotation: f32,
+    #[serde(default = "default_intensity")]
+    pub intensity: f32,
+}
+
+fn default_intensity() -> f32 {
+    1.0
+}
+
+pub fn deserialize_environment_map<'de, D>(deserializer: D) -> Result<EnvironmentMap, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let settings = EnvironmentMapSettings::deserialize(deserializer)?;
+    let image = image::open(&settings.path).map_err(|err| {
+        serde::de::Error::custom(format!(
+            "failed to load environment map {}: {err}",
+            settings.path.display()
+        ))
+    })?;
+    Ok(EnvironmentMap::new(settings, image.into_rgb32f()))
+}
+
+pub fn serialize_environment_map<S>(map: &EnvironmentMap, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    map.settings.serialize(serializer)
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -69,45 +363,145 @@ impl CameraSettings {
     }
 }
 
+impl From<&Camera> for CameraSettings {
+    fn from(camera: &Camera) -> Self {
+        Self {
+            position: camera.position.into(),
+            yaw: camera.yaw,
+            pitch: camera.pitch,
+            fov: camera.fov,
+            focus_distance: camera.focus_distance,
+            defocus_angle: camera.defocus_angle,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case", tag = "mesh_type")]
 pub enum TriangleMeshGeometrySettings {
     ObjFile {
         path: PathBuf,
     },
+    Gltf {
+        path: PathBuf,
+    },
+    PlyFile {
+        path: PathBuf,
+    },
+    StlFile {
+        path: PathBuf,
+    },
     Implicit {
         verts: Vec<(f32, f32, f32)>,
         indices: Vec<(u32, u32, u32)>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        normals: Vec<(f32, f32, f32)>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        tex_coords: Vec<(f32, f32)>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        colors: Vec<(f32, f32, f32)>,
     },
 }
 
 impl TryInto<TriangleMeshGeometry> for TriangleMeshGeometrySettings {
-    type Error = tobj::LoadError;
+    type Error = anyhow::Error;
 
     fn try_into(self) -> Result<TriangleMeshGeometry, Self::Error> {
-        Ok(match self {
-            TriangleMeshGeometrySettings::ObjFile { path } => {
-                let (models, _materials) = tobj::load_obj(path, &tobj::LoadOptions::default())?;
-                let model = models.get(0).expect("obj has no models");
-
-                TriangleMeshGeometry {
-                    verts: model
-                        .mesh
-                        .positions
-                        .chunks_exact(3)
-                        .map(|chunk| (chunk[0], chunk[1], chunk[2]))
-                        .collect(),
-                    indices: model
-                        .mesh
-                        .indices
-                        .chunks_exact(3)
-                        .map(|chunk| (chunk[0], chunk[1], chunk[2]))
-                        .collect(),
-                }
-            }
-            TriangleMeshGeometrySettings::Implicit { verts, indices } => {
-                TriangleMeshGeometry { verts, indices }
-            }
-        })
+        let mesh = match self {
+            TriangleMeshGeometrySettings::ObjFile { path } => obj::load_mesh(&path)?,
+            TriangleMeshGeometrySettings::Gltf { path } => gltf::load_mesh(&path)?,
+            TriangleMeshGeometrySettings::PlyFile { path } => ply::load_mesh(&path)?,
+            TriangleMeshGeometrySettings::StlFile { path } => stl::load_mesh(&path)?,
+            TriangleMeshGeometrySettings::Implicit {
+                verts,
+                indices,
+                normals,
+                tex_coords,
+                colors,
+            } => TriangleMeshGeometry {
+                verts,
+                indices,
+                normals,
+                tex_coords,
+                colors,
+            },
+        };
+        validate_triangle_mesh(mesh)
+    }
+}
+
+/// Concatenates meshes, only keeping the vertex attributes every mesh has.
+fn merge_meshes(meshes: Vec<TriangleMeshGeometry>) -> TriangleMeshGeometry {
+    let has_normals = meshes.iter().all(|mesh| !mesh.normals.is_empty());
+    let has_tex_coords = meshes.iter().all(|mesh| !mesh.tex_coords.is_empty());
+    let has_colors = meshes.iter().all(|mesh| !mesh.colors.is_empty());
+
+    let mut merged = TriangleMeshGeometry {
+        verts: Vec::new(),
+        indices: Vec::new(),
+        normals: Vec::new(),
+        tex_coords: Vec::new(),
+        colors: Vec::new(),
+    };
+    for mesh in meshes {
+        let offset = merged.verts.len() as u32;
+        merged.verts.extend(mesh.verts);
+        merged.indices.extend(
+            mesh.indices
+                .into_iter()
+                .map(|(a, b, c)| (a + offset, b + offset, c + offset)),
+        );
+        if has_normals {
+            merged.normals.extend(mesh.normals);
+        }
+        if has_tex_coords {
+            merged.tex_coords.extend(mesh.tex_coords);
+        }
+        if has_colors {
+            merged.colors.extend(mesh.colors);
+        }
+    }
+    merged
+}
+
+/// Used by imported geometry without a material.
+fn default_material() -> MaterialType {
+    MaterialType::Lambertian {
+        texture: Texture::Solid {
+            color: Vec3::splat(0.8).extend(1.0),
+        },
+    }
+}
+
+fn validate_triangle_mesh(mesh: TriangleMeshGeometry) -> anyhow::Result<TriangleMeshGeometry> {
+    let vertex_count = mesh.verts.len();
+    if !mesh.normals.is_empty() && mesh.normals.len() != vertex_count {
+        anyhow::bail!(
+            "the mesh has {} normals but {vertex_count} vertices",
+            mesh.normals.len()
+        );
+    }
+    if !mesh.tex_coords.is_empty() && mesh.tex_coords.len() != vertex_count {
+        anyhow::bail!(
+            "the mesh has {} texture coordinates but {vertex_count} vertices",
+            mesh.tex_coords.len()
+        );
+    }
+    if !mesh.colors.is_empty() && mesh.colors.len() != vertex_count {
+        anyhow::bail!(
+            "the mesh has {} colors but {vertex_count} vertices",
+            mesh.colors.len()
+        );
+    }
+    for (triangle, (a, b, c)) in mesh.indices.iter().enumerate() {
+        if [a, b, c]
+            .iter()
+            .any(|&&index| index as usize >= vertex_count)
+        {
+            anyhow::bail!(
+                "triangle {triangle} references vertices ({a}, {b}, {c}) but the mesh only has {vertex_count} vertices"
+            );
+        }
     }
+    Ok(mesh)
 }
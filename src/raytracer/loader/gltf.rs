@@ -0,0 +1,539 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::Context;
+use glam::{Affine3A, Mat4, Vec3, Vec4};
+use serde::Deserialize;
+
+use crate::raytracer::{
+    material::{
+        MaterialType,
+        texture::{ColorSpace, Filter, ImageTexture, Sampler, Texture, WrapMode},
+    },
+    world::{Geometry, GeometryType, Transform, TriangleMeshGeometry},
+};
+
+use super::{CameraSettings, default_material, merge_meshes, validate_triangle_mesh};
+
+/// Focus distance given to imported cameras. glTF cameras are pinholes, so without a defocus
+/// angle it only sets the size of the viewport and doesn't change the image.
+const CAMERA_FOCUS_DISTANCE: f32 = 10.0;
+
+/// Every mesh of the default scene of a glTF file, placed by its node hierarchy with its
+/// metallic-roughness materials converted.
+#[derive(Deserialize)]
+pub(super) struct GltfSceneSettings {
+    path: PathBuf,
+    /// Materials replacing the glTF ones, by material name.
+    #[serde(default)]
+    materials: BTreeMap<String, MaterialType>,
+    #[serde(default)]
+    transform: Transform,
+}
+
+/// Geometry imported from a glTF scene, with its first camera if it has one.
+pub(super) struct GltfScene {
+    pub geometry: Vec<Geometry>,
+    pub camera: Option<CameraSettings>,
+}
+
+impl GltfSceneSettings {
+    pub(super) fn load(self) -> anyhow::Result<GltfScene> {
+        let import = gltf::import(&self.path)
+            .with_context(|| format!("failed to load gltf file {}", self.path.display()))?;
+        self.convert(import)
+    }
+
+    /// Converts a glTF document with its buffers and images already loaded.
+    fn convert(
+        self,
+        (document, buffers, images): (
+            gltf::Document,
+            Vec<gltf::buffer::Data>,
+            Vec<gltf::image::Data>,
+        ),
+    ) -> anyhow::Result<GltfScene> {
+        if let Some(name) = self.materials.keys().find(|name| {
+            !document
+                .materials()
+                .any(|material| material.name() == Some(name.as_str()))
+        }) {
+            anyhow::bail!("no material named {name} in {}", self.path.display());
+        }
+
+        let materials = document
+            .materials()
+            .map(|material| {
+                let name = material.name().unwrap_or_default();
+                match self.materials.get(name) {
+                    Some(material) => Ok(material.clone()),
+                    None => convert_material(&material, &images)
+                        .with_context(|| format!("material {name}")),
+                }
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        // Meshes used by several nodes are shared, so tracers store them once
+        let mut meshes: HashMap<(usize, usize), Arc<TriangleMeshGeometry>> = HashMap::new();
+        let mut geometry = Vec::new();
+        let mut camera = None;
+
+        for (node, node_to_scene) in scene_nodes(&document)? {
+            let object_to_world = self.transform.object_to_world * node_to_scene;
+
+            if let Some(node_camera) = node.camera() {
+                camera = camera.or_else(|| convert_camera(&node_camera, object_to_world));
+            }
+
+            let Some(mesh) = node.mesh() else {
+                continue;
+            };
+            if object_to_world.matrix3.determinant().abs() < 1e-12 {
+                anyhow::bail!("the transform of node {} is not invertible", node.index());
+            }
+
+            for primitive in mesh.primitives() {
+                let key = (mesh.index(), primitive.index());
+                let triangle_mesh = match meshes.get(&key) {
+                    Some(triangle_mesh) => triangle_mesh.clone(),
+                    None => {
+                        let tex_coord_set = tex_coord_set(&primitive.material())
+                            .with_context(|| format!("mesh {}", mesh.index()))?;
+                        let Some(triangle_mesh) =
+                            load_primitive(&primitive, &buffers, tex_coord_set)
+                                .with_context(|| format!("mesh {}", mesh.index()))?
+                        else {
+                            continue;
+                        };
+                        let triangle_mesh = Arc::new(triangle_mesh);
+                        meshes.insert(key, triangle_mesh.clone());
+                        triangle_mesh
+                    }
+                };
+
+                geometry.push(Geometry {
+                    geometry_type: GeometryType::TriangleMesh(triangle_mesh),
+                    material: primitive
+                        .material()
+                        .index()
+                        .map_or_else(default_material, |index| materials[index].clone()),
+                    medium: None,
+                    transform: Transform::new(object_to_world),
+                });
+            }
+        }
+
+        Ok(GltfScene { geometry, camera })
+    }
+}
+
+/// Loads every mesh of the default scene of a glTF file as a single mesh, with the node
+/// transforms applied and ignoring its materials.
+pub(super) fn load_mesh(path: &Path) -> anyhow::Result<TriangleMeshGeometry> {
+    let (document, buffers, _images) = gltf::import(path)
+        .with_context(|| format!("failed to load gltf file {}", path.display()))?;
+
+    let mut meshes = Vec::new();
+    for (node, node_to_scene) in scene_nodes(&document)? {
+        let Some(mesh) = node.mesh() else {
+            continue;
+        };
+        let transform = Transform::new(node_to_scene);
+        for primitive in mesh.primitives() {
+            if let Some(triangle_mesh) = load_primitive(&primitive, &buffers, 0)
+                .with_context(|| format!("mesh {}", mesh.index()))?
+            {
+                meshes.push(apply_transform(triangle_mesh, &transform));
+            }
+        }
+    }
+
+    if meshes.is_empty() {
+        anyhow::bail!("gltf file {} has no triangle meshes", path.display());
+    }
+    Ok(merge_meshes(meshes))
+}
+
+/// Flattens the node hierarchy of the default scene, with the transform from each node to
+/// the scene.
+fn scene_nodes(document: &gltf::Document) -> anyhow::Result<Vec<(gltf::Node<'_>, Affine3A)>> {
+    let scene = document
+        .default_scene()
+        .or_else(|| document.scenes().next())
+        .context("the file has no scenes")?;
+
+    // Nodes are pushed in reverse so they are visited in document order, which decides the
+    // first camera
+    let mut nodes = Vec::new();
+    let mut stack: Vec<_> = scene
+        .nodes()
+        .map(|node| (node, Affine3A::IDENTITY))
+        .collect();
+    stack.reverse();
+    while let Some((node, parent_to_scene)) = stack.pop() {
+        let node_to_parent =
+            Affine3A::from_mat4(Mat4::from_cols_array_2d(&node.transform().matrix()));
+        let node_to_scene = parent_to_scene * node_to_parent;
+        let first_child = stack.len();
+        stack.extend(node.children().map(|child| (child, node_to_scene)));
+        stack[first_child..].reverse();
+        nodes.push((node, node_to_scene));
+    }
+    Ok(nodes)
+}
+
+/// Reads an indexed triangle primitive with the texture coordinates of `tex_coord_set`,
+/// returning `None` for points and lines.
+fn load_primitive(
+    primitive: &gltf::Primitive,
+    buffers: &[gltf::buffer::Data],
+    tex_coord_set: u32,
+) -> anyhow::Result<Option<TriangleMeshGeometry>> {
+    if primitive.mode() != gltf::mesh::Mode::Triangles {
+        return Ok(None);
+    }
+
+    let reader = primitive.reader(|buffer| buffers.get(buffer.index()).map(|data| &data[..]));
+    let verts: Vec<(f32, f32, f32)> = reader
+        .read_positions()
+        .context("primitive has no positions")?
+        .map(|[x, y, z]| (x, y, z))
+        .collect();
+    let indices: Vec<u32> = match reader.read_indices() {
+        Some(indices) => indices.into_u32().collect(),
+        None => (0..verts.len() as u32).collect(),
+    };
+    let normals = reader
+        .read_normals()
+        .map(|normals| normals.map(|[x, y, z]| (x, y, z)).collect())
+        .unwrap_or_default();
+    // glTF puts the origin of textures at the top left, unlike image textures here
+    let tex_coords = reader
+        .read_tex_coords(tex_coord_set)
+        .map(|tex_coords| tex_coords.into_f32().map(|[u, v]| (u, 1.0 - v)).collect())
+        .unwrap_or_default();
+
+    let mesh = TriangleMeshGeometry {
+        verts,
+        indices: indices
+            .chunks_exact(3)
+            .map(|chunk| (chunk[0], chunk[1], chunk[2]))
+            .collect(),
+        normals,
+        tex_coords,
+        colors: Vec::new(),
+    };
+    validate_triangle_mesh(mesh).map(Some)
+}
+
+/// Texture coordinate set the textures of a material are mapped with. Meshes only keep one
+/// set, so the textures must agree on it.
+fn tex_coord_set(material: &gltf::Material) -> anyhow::Result<u32> {
+    let pbr = material.pbr_metallic_roughness();
+    let mut sets = [
+        pbr.base_color_texture().map(|info| info.tex_coord()),
+        pbr.metallic_roughness_texture()
+            .map(|info| info.tex_coord()),
+        material.emissive_texture().map(|info| info.tex_coord()),
+    ]
+    .into_iter()
+    .flatten();
+
+    let set = sets.next().unwrap_or(0);
+    if sets.any(|other| other != set) {
+        anyhow::bail!(
+            "the textures of material {} use different texture coordinate sets",
+            material.name().unwrap_or("without a name")
+        );
+    }
+    Ok(set)
+}
+
+fn apply_transform(mut mesh: TriangleMeshGeometry, transform: &Transform) -> TriangleMeshGeometry {
+    for vert in &mut mesh.verts {
+        *vert = transform.point_to_world(Vec3::from(*vert)).into();
+    }
+    for normal in &mut mesh.normals {
+        *normal = transform.normal_to_world(Vec3::from(*normal)).into();
+    }
+    mesh
+}
+
+/// Converts a metallic-roughness material, baking its factors into its textures.
+fn convert_material(
+    material: &gltf::Material,
+    images: &[gltf::image::Data],
+) -> anyhow::Result<MaterialType> {
+    // Colors are sRGB encoded, everything else is linear
+    let image = |texture: &gltf::Texture, color_space: ColorSpace| {
+        let mut image = convert_image(&images[texture.source().index()])?;
+        color_space.decode(&mut image);
+        anyhow::Ok(image)
+    };
+    let pbr = material.pbr_metallic_roughness();
+
+    let base_factor = Vec4::from(pbr.base_color_factor());
+    let base_image = pbr
+        .base_color_texture()
+        .map(|info| {
+            let texture = info.texture();
+            anyhow::Ok((
+                image(&texture, ColorSpace::Srgb)?,
+                convert_sampler(&texture.sampler()),
+            ))
+        })
+        .transpose()?;
+    let base_color = match &base_image {
+        Some((base_image, sampler)) => Texture::Image(ImageTexture::new(
+            map_pixels(base_image, |pixel| pixel * base_factor),
+            sampler.clone(),
+        )),
+        None => Texture::Solid { color: base_factor },
+    };
+
+    // Roughness is in the green channel and metalness in the blue one
+    let (metallic, roughness) = match pbr.metallic_roughness_texture() {
+        Some(info) => {
+            let texture = info.texture();
+            let metallic_roughness = image(&texture, ColorSpace::Linear)?;
+            let sampler = convert_sampler(&texture.sampler());
+            let metallic_factor = pbr.metallic_factor();
+            let roughness_factor = pbr.roughness_factor();
+            (
+                Texture::Image(ImageTexture::new(
+                    map_pixels(&metallic_roughness, |pixel| {
+                        Vec4::splat(pixel.z * metallic_factor)
+                    }),
+                    sampler.clone(),
+                )),
+                Texture::Image(ImageTexture::new(
+                    map_pixels(&metallic_roughness, |pixel| {
+                        Vec4::splat(pixel.y * roughness_factor)
+                    }),
+                    sampler,
+                )),
+            )
+        }
+        None => (
+            Texture::Solid {
+                color: Vec4::splat(pbr.metallic_factor()),
+            },
+            Texture::Solid {
+                color: Vec4::splat(pbr.roughness_factor()),
+            },
+        ),
+    };
+
+    let emissive_factor = Vec3::from(material.emissive_factor()).extend(1.0);
+    let emission = match material.emissive_texture() {
+        Some(info) => {
+            let texture = info.texture();
+            Texture::Image(ImageTexture::new(
+                map_pixels(&image(&texture, ColorSpace::Srgb)?, |pixel| {
+                    pixel * emissive_factor
+                }),
+                convert_sampler(&texture.sampler()),
+            ))
+        }
+        None => Texture::Solid {
+            color: emissive_factor,
+        },
+    };
+
+    // Opacity comes from the alpha of the base color, unless the material is opaque
+    let opacity = |alpha: f32| match material.alpha_mode() {
+        gltf::material::AlphaMode::Opaque => 1.0,
+        gltf::material::AlphaMode::Mask => {
+            if alpha >= material.alpha_cutoff().unwrap_or(0.5) {
+                1.0
+            } else {
+                0.0
+            }
+        }
+        gltf::material::AlphaMode::Blend => alpha,
+    };
+    let opacity = match &base_image {
+        Some((base_image, sampler))
+            if material.alpha_mode() != gltf::material::AlphaMode::Opaque =>
+        {
+            Texture::Image(ImageTexture::new(
+                map_pixels(base_image, |pixel| {
+                    Vec4::splat(opacity(pixel.w * base_factor.w))
+                }),
+                sampler.clone(),
+            ))
+        }
+        _ => Texture::Solid {
+            color: Vec4::splat(opacity(base_factor.w)),
+        },
+    };
+
+    Ok(MaterialType::Principled {
+        base_color,
+        metallic,
+        roughness,
+        emission,
+        emission_intensity: material.emissive_strength().unwrap_or(1.0),
+        opacity,
+    })
+}
+
+/// Converts the wrap modes and filter of a sampler.
+fn convert_sampler(sampler: &gltf::texture::Sampler) -> Sampler {
+    use gltf::texture::{MagFilter, WrappingMode};
+
+    let wrap = |mode| match mode {
+        WrappingMode::Repeat => WrapMode::Repeat,
+        WrappingMode::MirroredRepeat => WrapMode::Mirror,
+        WrappingMode::ClampToEdge => WrapMode::Clamp,
+    };
+    Sampler {
+        wrap: wrap(sampler.wrap_s()),
+        wrap_v: Some(wrap(sampler.wrap_t())),
+        filter: match sampler.mag_filter() {
+            Some(MagFilter::Nearest) => Filter::Nearest,
+            _ => Filter::Bilinear,
+        },
+        ..Sampler::default()
+    }
+}
+
+fn map_pixels(image: &image::Rgba32FImage, f: impl Fn(Vec4) -> Vec4) -> image::Rgba32FImage {
+    let mut image = image.clone();
+    for pixel in image.pixels_mut() {
+        pixel.0 = f(Vec4::from(pixel.0)).into();
+    }
+    image
+}
+
+fn convert_image(data: &gltf::image::Data) -> anyhow::Result<image::Rgba32FImage> {
+    use gltf::image::Format;
+
+    let (width, height) = (data.width, data.height);
+    let pixels = data.pixels.clone();
+    let image: Option<image::DynamicImage> = match data.format {
+        Format::R8 => image::GrayImage::from_raw(width, height, pixels).map(Into::into),
+        Format::R8G8 => image::GrayAlphaImage::from_raw(width, height, pixels).map(Into::into),
+        Format::R8G8B8 => image::RgbImage::from_raw(width, height, pixels).map(Into::into),
+        Format::R8G8B8A8 => image::RgbaImage::from_raw(width, height, pixels).map(Into::into),
+        format => anyhow::bail!("unsupported image format {format:?}"),
+    };
+    image
+        .map(|image| image.into_rgba32f())
+        .context("image data doesn't match its size")
+}
+
+/// Converts a perspective camera, looking down its -Z axis.
+fn convert_camera(camera: &gltf::Camera, camera_to_world: Affine3A) -> Option<CameraSettings> {
+    let gltf::camera::Projection::Perspective(perspective) = camera.projection() else {
+        return None;
+    };
+
+    let position = camera_to_world.translation;
+    let direction = camera_to_world
+        .transform_vector3(Vec3::NEG_Z)
+        .try_normalize()?;
+    Some(CameraSettings {
+        position: position.into(),
+        yaw: direction.z.atan2(direction.x).to_degrees(),
+        pitch: direction.y.clamp(-1.0, 1.0).asin().to_degrees(),
+        fov: perspective.yfov().to_degrees(),
+        focus_distance: CAMERA_FOCUS_DISTANCE,
+        defocus_angle: 0.0,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::GltfSceneSettings;
+    use crate::raytracer::material::MaterialType;
+    use crate::raytracer::material::texture::{Filter, Texture, WrapMode};
+    use glam::Vec4;
+    use std::path::PathBuf;
+
+    /// A camera tilted 30° up and a textured triangle. The texture is a red and a blue texel,
+    /// clamped along u and mirrored along v, and its material halves the red.
+    const GLTF: &str = r#"{
+        "asset": { "version": "2.0" },
+        "scene": 0,
+        "scenes": [{ "nodes": [0, 1] }],
+        "nodes": [
+            {
+                "camera": 0,
+                "translation": [1.0, 2.0, 3.0],
+                "rotation": [0.258819, 0.0, 0.0, 0.9659258]
+            },
+            { "mesh": 0 }
+        ],
+        "cameras": [{ "type": "perspective", "perspective": { "yfov": 0.8, "znear": 0.1 } }],
+        "meshes": [{ "primitives": [{ "attributes": { "POSITION": 0, "TEXCOORD_0": 1 }, "material": 0 }] }],
+        "materials": [{
+            "pbrMetallicRoughness": {
+                "baseColorTexture": { "index": 0 },
+                "baseColorFactor": [0.5, 1.0, 1.0, 1.0]
+            }
+        }],
+        "textures": [{ "source": 0, "sampler": 0 }],
+        "samplers": [{ "magFilter": 9728, "wrapS": 33071, "wrapT": 33648 }],
+        "images": [{ "bufferView": 2, "mimeType": "image/png" }],
+        "accessors": [
+            {
+                "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3",
+                "min": [0.0, 0.0, 0.0], "max": [1.0, 1.0, 0.0]
+            },
+            { "bufferView": 1, "componentType": 5126, "count": 3, "type": "VEC2" }
+        ],
+        "bufferViews": [
+            { "buffer": 0, "byteOffset": 0, "byteLength": 36 },
+            { "buffer": 0, "byteOffset": 36, "byteLength": 24 },
+            { "buffer": 0, "byteOffset": 60, "byteLength": 71 }
+        ],
+        "buffers": [{
+            "byteLength": 131,
+            "uri": "data:application/octet-stream;base64,AAAAAAAAAAAAAAAAAACAPwAAAAAAAAAAAAAAAAAAgD8AAAAAAAAAAAAAAAAAAIA/AAAAAAAAAAAAAIA/iVBORw0KGgoAAAANSUhEUgAAAAIAAAABCAYAAAD0In+KAAAADklEQVR4nGP4z8AAQv8BD/kD/YURmXYAAAAASUVORK5CYII="
+        }]
+    }"#;
+
+    #[test]
+    fn embedded_scene() {
+        let settings = GltfSceneSettings {
+            path: PathBuf::from("embedded.gltf"),
+            materials: Default::default(),
+            transform: Default::default(),
+        };
+        let scene = settings.convert(gltf::import_slice(GLTF).unwrap()).unwrap();
+
+        // Cameras look down their -Z axis, here turned towards -Z in the world and up
+        let camera = scene.camera.unwrap();
+        assert_eq!(camera.position, (1.0, 2.0, 3.0));
+        assert!((camera.yaw - -90.0).abs() < 1e-3, "yaw {}", camera.yaw);
+        assert!((camera.pitch - 30.0).abs() < 1e-3, "pitch {}", camera.pitch);
+        assert!(
+            (camera.fov - 0.8_f32.to_degrees()).abs() < 1e-3,
+            "fov {}",
+            camera.fov
+        );
+
+        assert_eq!(scene.geometry.len(), 1);
+        let MaterialType::Principled {
+            base_color: Texture::Image(base_color),
+            ..
+        } = &scene.geometry[0].material
+        else {
+            panic!("the base color isn't an image texture");
+        };
+        let sampler = base_color.sampler();
+        assert!(matches!(sampler.wrap, WrapMode::Clamp));
+        assert!(matches!(sampler.wrap_v, Some(WrapMode::Mirror)));
+        assert!(matches!(sampler.filter, Filter::Nearest));
+
+        // The factor is baked into the decoded texels, and u is clamped past the blue one rather
+        // than repeated back to the red one
+        let close = |a: Vec4, b: Vec4| (a - b).abs().max_element() < 1e-4;
+        let red = base_color.sample((0.25, 0.5), None);
+        assert!(close(red, Vec4::new(0.5, 0.0, 0.0, 1.0)), "{red}");
+        let blue = base_color.sample((1.25, 0.5), None);
+        assert!(close(blue, Vec4::new(0.0, 0.0, 1.0, 1.0)), "{blue}");
+    }
+}
@@ -0,0 +1,205 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use glam::Vec3;
+use serde::Deserialize;
+
+use crate::raytracer::{
+    material::{
+        MaterialType,
+        texture::{ColorSpace, ImageTexture, Sampler, Texture},
+    },
+    world::{Geometry, GeometryType, Transform, TriangleMeshGeometry},
+};
+
+use super::{default_material, merge_meshes, validate_triangle_mesh};
+
+/// Every object of an OBJ file placed as its own geometry, with the materials of its MTL file.
+#[derive(Deserialize)]
+pub(super) struct ObjSceneSettings {
+    path: PathBuf,
+    /// Materials replacing the MTL ones, by object name.
+    #[serde(default)]
+    materials: BTreeMap<String, MaterialType>,
+    #[serde(default)]
+    transform: Transform,
+}
+
+impl ObjSceneSettings {
+    pub(super) fn load(self) -> anyhow::Result<Vec<Geometry>> {
+        let (models, materials) = tobj::load_obj(&self.path, &load_options())
+            .with_context(|| format!("failed to load obj file {}", self.path.display()))?;
+        let materials = materials
+            .with_context(|| format!("failed to load the materials of {}", self.path.display()))?;
+
+        if let Some(name) = self
+            .materials
+            .keys()
+            .find(|name| !models.iter().any(|model| &model.name == *name))
+        {
+            anyhow::bail!("no object named {name} in {}", self.path.display());
+        }
+
+        let directory = self.path.parent().unwrap_or(Path::new(""));
+        let materials = materials
+            .iter()
+            .map(|material| {
+                convert_material(material, directory)
+                    .with_context(|| format!("material {}", material.name))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        models
+            .iter()
+            .map(|model| {
+                let mesh = validate_triangle_mesh(mesh_from_obj(&model.mesh))
+                    .with_context(|| format!("object {}", model.name))?;
+                let material = match self.materials.get(&model.name) {
+                    Some(material) => material.clone(),
+                    None => model
+                        .mesh
+                        .material_id
+                        .and_then(|id| materials.get(id).cloned())
+                        .unwrap_or_else(default_material),
+                };
+
+                Ok(Geometry {
+                    geometry_type: GeometryType::TriangleMesh(mesh.into()),
+                    material,
+                    medium: None,
+                    transform: self.transform,
+                })
+            })
+            .collect()
+    }
+}
+
+pub(super) fn load_options() -> tobj::LoadOptions {
+    // A single index lets the normals and texture coordinates share the indices of the positions
+    tobj::LoadOptions {
+        single_index: true,
+        triangulate: true,
+        ..Default::default()
+    }
+}
+
+/// Loads every object of an OBJ file as a single mesh, ignoring its materials.
+pub(super) fn load_mesh(path: &Path) -> anyhow::Result<TriangleMeshGeometry> {
+    let (models, _materials) = tobj::load_obj(path, &load_options())
+        .with_context(|| format!("failed to load obj file {}", path.display()))?;
+    if models.is_empty() {
+        anyhow::bail!("obj file {} has no models", path.display());
+    }
+
+    Ok(merge_meshes(
+        models
+            .iter()
+            .map(|model| mesh_from_obj(&model.mesh))
+            .collect(),
+    ))
+}
+
+fn mesh_from_obj(mesh: &tobj::Mesh) -> TriangleMeshGeometry {
+    TriangleMeshGeometry {
+        verts: mesh
+            .positions
+            .chunks_exact(3)
+            .map(|chunk| (chunk[0], chunk[1], chunk[2]))
+            .collect(),
+        indices: mesh
+            .indices
+            .chunks_exact(3)
+            .map(|chunk| (chunk[0], chunk[1], chunk[2]))
+            .collect(),
+        normals: mesh
+            .normals
+            .chunks_exact(3)
+            .map(|chunk| (chunk[0], chunk[1], chunk[2]))
+            .collect(),
+        tex_coords: mesh
+            .texcoords
+            .chunks_exact(2)
+            .map(|chunk| (chunk[0], chunk[1]))
+            .collect(),
+        colors: Vec::new(),
+    }
+}
+
+/// Picks the closest material: emissive when it has an emission color, dielectric when it is
+/// transparent, metal when it is more specular than diffuse and lambertian otherwise.
+fn convert_material(material: &tobj::Material, directory: &Path) -> anyhow::Result<MaterialType> {
+    let emission = match material.unknown_param.get("Ke") {
+        Some(value) => parse_color(value).context("invalid Ke")?,
+        None => Vec3::ZERO,
+    };
+    if emission.max_element() > 0.0 {
+        let intensity = emission.max_element();
+        return Ok(MaterialType::Emissive {
+            color: Texture::Solid {
+                color: (emission / intensity).extend(1.0),
+            },
+            intensity,
+            two_sided: true,
+            base: None,
+        });
+    }
+
+    let dissolve = match (material.dissolve, material.unknown_param.get("Tr")) {
+        (Some(dissolve), _) => dissolve,
+        (None, Some(transparency)) => {
+            1.0 - transparency.trim().parse::<f32>().context("invalid Tr")?
+        }
+        (None, None) => 1.0,
+    };
+    if dissolve < 1.0 || matches!(material.illumination_model, Some(4 | 6 | 7)) {
+        let transmittance = match material.unknown_param.get("Tf") {
+            Some(value) => parse_color(value).context("invalid Tf")?,
+            None => Vec3::ONE,
+        };
+        return Ok(MaterialType::Dielectric {
+            refractive_index: material.optical_density.unwrap_or(1.5),
+            transmittance: transmittance.extend(1.0),
+            absorption_distance: 1.0,
+            abbe_number: None,
+        });
+    }
+
+    let diffuse = Vec3::from(material.diffuse.unwrap_or([0.8; 3]));
+    let specular = Vec3::from(material.specular.unwrap_or([0.0; 3]));
+    if specular.max_element() > diffuse.max_element() {
+        // Usual conversion from the Phong exponent to a roughness
+        let shininess = material.shininess.unwrap_or(0.0).max(0.0);
+        return Ok(MaterialType::Metal {
+            albedo: specular.extend(1.0),
+            fuzziness: (2.0 / (shininess + 2.0)).sqrt(),
+        });
+    }
+
+    let texture = match &material.diffuse_texture {
+        Some(texture) => {
+            let path = directory.join(texture);
+            let mut image = image::open(&path)
+                .with_context(|| format!("failed to load image {}", path.display()))?
+                .into_rgba32f();
+            ColorSpace::Srgb.decode(&mut image);
+            Texture::Image(ImageTexture::new(image, Sampler::default()))
+        }
+        None => Texture::Solid {
+            color: diffuse.extend(1.0),
+        },
+    };
+    Ok(MaterialType::Lambertian { texture })
+}
+
+fn parse_color(value: &str) -> anyhow::Result<Vec3> {
+    let components = value
+        .split_whitespace()
+        .map(str::parse)
+        .collect::<Result<Vec<f32>, _>>()?;
+    match components[..] {
+        [value] => Ok(Vec3::splat(value)),
+        [r, g, b] => Ok(Vec3::new(r, g, b)),
+        _ => anyhow::bail!("expected 1 or 3 components, got {}", components.len()),
+    }
+}
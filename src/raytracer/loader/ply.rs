@@ -0,0 +1,419 @@
+use std::path::Path;
+
+use anyhow::Context;
+
+use crate::raytracer::{material::texture::ColorSpace, world::TriangleMeshGeometry};
+
+/// Loads the `vertex` and `face` elements of an ASCII or binary PLY file, with the vertex
+/// normals and colors when it has them. Polygons are triangulated as fans.
+pub(super) fn load_mesh(path: &Path) -> anyhow::Result<TriangleMeshGeometry> {
+    let data = std::fs::read(path)
+        .with_context(|| format!("failed to read ply file {}", path.display()))?;
+    parse(&data).with_context(|| format!("invalid ply file {}", path.display()))
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Ascii,
+    BinaryLittleEndian,
+    BinaryBigEndian,
+}
+
+#[derive(Clone, Copy)]
+enum Scalar {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+impl Scalar {
+    fn parse(name: &str) -> anyhow::Result<Self> {
+        Ok(match name {
+            "char" | "int8" => Scalar::I8,
+            "uchar" | "uint8" => Scalar::U8,
+            "short" | "int16" => Scalar::I16,
+            "ushort" | "uint16" => Scalar::U16,
+            "int" | "int32" => Scalar::I32,
+            "uint" | "uint32" => Scalar::U32,
+            "float" | "float32" => Scalar::F32,
+            "double" | "float64" => Scalar::F64,
+            _ => anyhow::bail!("unknown property type {name}"),
+        })
+    }
+
+    fn size(self) -> usize {
+        match self {
+            Scalar::I8 | Scalar::U8 => 1,
+            Scalar::I16 | Scalar::U16 => 2,
+            Scalar::I32 | Scalar::U32 | Scalar::F32 => 4,
+            Scalar::F64 => 8,
+        }
+    }
+}
+
+enum PropertyType {
+    Scalar(Scalar),
+    List { count: Scalar, item: Scalar },
+}
+
+struct Property {
+    name: String,
+    property_type: PropertyType,
+}
+
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+}
+
+impl Element {
+    fn property(&self, name: &str) -> Option<usize> {
+        self.properties
+            .iter()
+            .position(|property| property.name == name)
+    }
+}
+
+fn parse(data: &[u8]) -> anyhow::Result<TriangleMeshGeometry> {
+    const END_HEADER: &[u8] = b"end_header";
+    let header_end = data
+        .windows(END_HEADER.len())
+        .position(|window| window == END_HEADER)
+        .context("missing end_header")?;
+    // The body starts after the line break that ends the header
+    let body_start = data[header_end..]
+        .iter()
+        .position(|&byte| byte == b'\n')
+        .map_or(data.len(), |offset| header_end + offset + 1);
+    let header = std::str::from_utf8(&data[..header_end]).context("header is not valid text")?;
+    let (format, elements) = parse_header(header)?;
+
+    let mut body = match format {
+        Format::Ascii => Body::Ascii(
+            std::str::from_utf8(&data[body_start..])
+                .context("ascii body is not valid text")?
+                .split_ascii_whitespace(),
+        ),
+        _ => Body::Binary {
+            data: &data[body_start..],
+            big_endian: format == Format::BinaryBigEndian,
+        },
+    };
+
+    let mut mesh = TriangleMeshGeometry {
+        verts: Vec::new(),
+        indices: Vec::new(),
+        normals: Vec::new(),
+        tex_coords: Vec::new(),
+        colors: Vec::new(),
+    };
+    let mut values = Vec::new();
+    // Items of each list property of the current row, by property index
+    let mut lists = Vec::new();
+
+    for element in &elements {
+        let position = ["x", "y", "z"].map(|name| element.property(name));
+        let normal = ["nx", "ny", "nz"].map(|name| element.property(name));
+        let color = ["red", "green", "blue"].map(|name| element.property(name));
+        // Index of the vertex index list of faces
+        let face = if element.name == "face" {
+            let face = element
+                .property("vertex_indices")
+                .or_else(|| element.property("vertex_index"))
+                .context("faces have no vertex_indices property")?;
+            if !matches!(
+                element.properties[face].property_type,
+                PropertyType::List { .. }
+            ) {
+                anyhow::bail!("vertex_indices of faces must be a list");
+            }
+            Some(face)
+        } else {
+            None
+        };
+        lists.resize_with(element.properties.len(), Vec::new);
+
+        for row in 0..element.count {
+            values.clear();
+            for (property, list) in element.properties.iter().zip(&mut lists) {
+                let value = body.read_property(property, list).with_context(|| {
+                    format!("{} {row}, property {}", element.name, property.name)
+                })?;
+                values.push(value);
+            }
+
+            match (element.name.as_str(), face) {
+                ("vertex", _) => {
+                    let [Some(x), Some(y), Some(z)] = position else {
+                        anyhow::bail!("vertices have no x, y and z properties");
+                    };
+                    mesh.verts
+                        .push((values[x] as f32, values[y] as f32, values[z] as f32));
+                    if let [Some(x), Some(y), Some(z)] = normal {
+                        mesh.normals
+                            .push((values[x] as f32, values[y] as f32, values[z] as f32));
+                    }
+                    if let [Some(r), Some(g), Some(b)] = color {
+                        let [r, g, b] =
+                            [r, g, b].map(|index| color_value(element, index, values[index]));
+                        mesh.colors.push((r, g, b));
+                    }
+                }
+                (_, Some(face)) => {
+                    let list = lists[face]
+                        .iter()
+                        .map(|&index| {
+                            // Negative and huge indices fail the conversion, fractional ones the filter
+                            u32::try_from(index as i64)
+                                .ok()
+                                .filter(|_| index.fract() == 0.0)
+                                .with_context(|| format!("face {row} has invalid vertex {index}"))
+                        })
+                        .collect::<anyhow::Result<Vec<u32>>>()?;
+                    if list.len() < 3 {
+                        anyhow::bail!("face {row} has less than 3 vertices");
+                    }
+                    for i in 1..list.len() - 1 {
+                        mesh.indices.push((list[0], list[i], list[i + 1]));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    Ok(mesh)
+}
+
+fn parse_header(header: &str) -> anyhow::Result<(Format, Vec<Element>)> {
+    let mut lines = header.lines().enumerate();
+    if lines.next().map(|(_, line)| line.trim()) != Some("ply") {
+        anyhow::bail!("missing ply magic number");
+    }
+
+    let mut format = None;
+    let mut elements: Vec<Element> = Vec::new();
+    for (number, line) in lines {
+        let error = || format!("header line {}: {line}", number + 1);
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words[..] {
+            [] | ["comment", ..] | ["obj_info", ..] => {}
+            ["format", name, "1.0"] => {
+                format = Some(match name {
+                    "ascii" => Format::Ascii,
+                    "binary_little_endian" => Format::BinaryLittleEndian,
+                    "binary_big_endian" => Format::BinaryBigEndian,
+                    _ => anyhow::bail!("unknown format {name}"),
+                });
+            }
+            ["element", name, count] => elements.push(Element {
+                name: name.to_owned(),
+                count: count.parse().with_context(error)?,
+                properties: Vec::new(),
+            }),
+            ["property", "list", count, item, name] => elements
+                .last_mut()
+                .with_context(error)?
+                .properties
+                .push(Property {
+                    name: name.to_owned(),
+                    property_type: PropertyType::List {
+                        count: Scalar::parse(count).with_context(error)?,
+                        item: Scalar::parse(item).with_context(error)?,
+                    },
+                }),
+            ["property", scalar, name] => {
+                elements
+                    .last_mut()
+                    .with_context(error)?
+                    .properties
+                    .push(Property {
+                        name: name.to_owned(),
+                        property_type: PropertyType::Scalar(
+                            Scalar::parse(scalar).with_context(error)?,
+                        ),
+                    })
+            }
+            _ => anyhow::bail!("unexpected {}", error()),
+        }
+    }
+
+    let format = format.context("missing format line")?;
+    Ok((format, elements))
+}
+
+/// Integer colors go from 0 to their maximum value and are sRGB encoded, as the colors of
+/// scanners and paint tools are. Float ones go from 0 to 1 and are taken as linear.
+fn color_value(element: &Element, index: usize, value: f64) -> f32 {
+    let max = match element.properties[index].property_type {
+        PropertyType::Scalar(Scalar::U8) => u8::MAX as f64,
+        PropertyType::Scalar(Scalar::U16) => u16::MAX as f64,
+        _ => return value as f32,
+    };
+    ColorSpace::Srgb.decode_value((value / max) as f32)
+}
+
+/// Values of the elements, as whitespace separated text or packed binary.
+enum Body<'a> {
+    Ascii(std::str::SplitAsciiWhitespace<'a>),
+    Binary { data: &'a [u8], big_endian: bool },
+}
+
+impl Body<'_> {
+    /// Reads a scalar property, or the length of a list property whose items go to `list`.
+    fn read_property(&mut self, property: &Property, list: &mut Vec<f64>) -> anyhow::Result<f64> {
+        match property.property_type {
+            PropertyType::Scalar(scalar) => self.read(scalar),
+            PropertyType::List { count, item } => {
+                let count = self.read(count)?;
+                list.clear();
+                for _ in 0..count as usize {
+                    list.push(self.read(item)?);
+                }
+                Ok(count)
+            }
+        }
+    }
+
+    fn read(&mut self, scalar: Scalar) -> anyhow::Result<f64> {
+        match self {
+            Body::Ascii(words) => {
+                let word = words.next().context("unexpected end of file")?;
+                word.parse()
+                    .with_context(|| format!("invalid number {word}"))
+            }
+            Body::Binary { data, big_endian } => {
+                let size = scalar.size();
+                if data.len() < size {
+                    anyhow::bail!("unexpected end of file");
+                }
+                let (bytes, rest) = data.split_at(size);
+                *data = rest;
+
+                let mut buffer = [0; 8];
+                buffer[..size].copy_from_slice(bytes);
+                if *big_endian {
+                    buffer[..size].reverse();
+                }
+                Ok(match scalar {
+                    Scalar::I8 => buffer[0] as i8 as f64,
+                    Scalar::U8 => buffer[0] as f64,
+                    Scalar::I16 => i16::from_le_bytes([buffer[0], buffer[1]]) as f64,
+                    Scalar::U16 => u16::from_le_bytes([buffer[0], buffer[1]]) as f64,
+                    Scalar::I32 => i32::from_le_bytes(buffer[..4].try_into().unwrap()) as f64,
+                    Scalar::U32 => u32::from_le_bytes(buffer[..4].try_into().unwrap()) as f64,
+                    Scalar::F32 => f32::from_le_bytes(buffer[..4].try_into().unwrap()) as f64,
+                    Scalar::F64 => f64::from_le_bytes(buffer),
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse;
+
+    const HEADER: &str = "element vertex 4
+property float x
+property float y
+property float z
+property uchar red
+property uchar green
+property uchar blue
+element face 2
+property list uchar int vertex_indices
+property list uchar float texcoord
+end_header
+";
+
+    /// A unit square split into a quad and a triangle over it, each vertex with a color.
+    const VERTICES: [([f32; 3], [u8; 3]); 4] = [
+        ([0.0, 0.0, 0.0], [255, 0, 0]),
+        ([1.0, 0.0, 0.0], [0, 255, 0]),
+        ([1.0, 1.0, 0.0], [0, 0, 255]),
+        ([0.0, 1.0, 0.0], [255, 255, 255]),
+    ];
+    const FACES: [&[i32]; 2] = [&[0, 1, 2, 3], &[3, 2, 1]];
+
+    fn check_mesh(data: &[u8]) {
+        let mesh = parse(data).unwrap();
+        let verts: Vec<_> = VERTICES.iter().map(|(p, _)| (p[0], p[1], p[2])).collect();
+        assert_eq!(mesh.verts, verts);
+        assert_eq!(mesh.indices, vec![(0, 1, 2), (0, 2, 3), (3, 2, 1)]);
+        assert_eq!(
+            mesh.colors,
+            vec![
+                (1.0, 0.0, 0.0),
+                (0.0, 1.0, 0.0),
+                (0.0, 0.0, 1.0),
+                (1.0, 1.0, 1.0)
+            ]
+        );
+        assert!(mesh.normals.is_empty());
+    }
+
+    fn binary(format: &str, to_bytes: fn(f32) -> [u8; 4], int_to_bytes: fn(i32) -> [u8; 4]) {
+        let mut data = format!("ply\nformat {format} 1.0\n{HEADER}").into_bytes();
+        for (position, color) in VERTICES {
+            for value in position {
+                data.extend(to_bytes(value));
+            }
+            data.extend(color);
+        }
+        for face in FACES {
+            data.push(face.len() as u8);
+            for &index in face {
+                data.extend(int_to_bytes(index));
+            }
+            // Texture coordinates after the indices
+            data.push(2);
+            data.extend(to_bytes(0.5));
+            data.extend(to_bytes(0.25));
+        }
+        check_mesh(&data);
+    }
+
+    fn ascii(faces: &str) -> String {
+        let mut data = format!("ply\nformat ascii 1.0\n{HEADER}");
+        for (position, color) in VERTICES {
+            data += &format!(
+                "{} {} {} {} {} {}\n",
+                position[0], position[1], position[2], color[0], color[1], color[2]
+            );
+        }
+        data + faces
+    }
+
+    #[test]
+    fn ascii_mesh() {
+        check_mesh(ascii("4 0 1 2 3 2 0.5 0.25\n3 3 2 1 0\n").as_bytes());
+    }
+
+    #[test]
+    fn binary_little_endian_mesh() {
+        binary("binary_little_endian", f32::to_le_bytes, i32::to_le_bytes);
+    }
+
+    #[test]
+    fn binary_big_endian_mesh() {
+        binary("binary_big_endian", f32::to_be_bytes, i32::to_be_bytes);
+    }
+
+    #[test]
+    fn invalid_indices() {
+        assert!(parse(ascii("3 0 1 -1 0\n3 3 2 1 0\n").as_bytes()).is_err());
+        assert!(parse(ascii("3 0 1 1.5 0\n3 3 2 1 0\n").as_bytes()).is_err());
+    }
+
+    #[test]
+    fn truncated_body() {
+        assert!(parse(ascii("4 0 1 2 3 2 0.5\n").as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,34 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use anyhow::Context;
+
+use crate::raytracer::world::TriangleMeshGeometry;
+
+/// Loads an ASCII or binary STL file. STL only stores face normals, so the mesh is flat shaded.
+pub(super) fn load_mesh(path: &Path) -> anyhow::Result<TriangleMeshGeometry> {
+    let file =
+        File::open(path).with_context(|| format!("failed to open stl file {}", path.display()))?;
+    let stl = stl_io::read_stl(&mut BufReader::new(file))
+        .with_context(|| format!("invalid stl file {}", path.display()))?;
+
+    Ok(TriangleMeshGeometry {
+        verts: stl
+            .vertices
+            .iter()
+            .map(|vertex| (vertex[0], vertex[1], vertex[2]))
+            .collect(),
+        indices: stl
+            .faces
+            .iter()
+            .map(|face| {
+                let [a, b, c] = face.vertices.map(|index| index as u32);
+                (a, b, c)
+            })
+            .collect(),
+        normals: Vec::new(),
+        tex_coords: Vec::new(),
+        colors: Vec::new(),
+    })
+}
@@ -1,14 +1,29 @@
+mod microfacet;
+mod noise;
+mod spectrum;
+
+use crate::raytracer::material::microfacet::{
+    Bsdf, Coating, Conductor, Frame, Plastic, Principled, roughness_to_alpha,
+};
+use crate::raytracer::material::spectrum::{cauchy_index, sample_wavelength};
 use crate::raytracer::material::texture::Texture;
+use crate::raytracer::medium::Medium;
 use crate::raytracer::tracer::TraceResult;
 use crate::raytracer::world::Ray;
 use glam::{Vec3, Vec4};
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::f32::consts::PI;
 
 pub struct ScatterResult {
     pub attenuation: Vec4,
     pub scattered: Ray,
+    /// Solid angle density of the scattered direction, or `None` for specular scattering,
+    /// which can't be combined with light sampling.
+    pub pdf: Option<f32>,
 }
 
+// Built once per geometry and shared, so the textures of principled materials can stay inline
+#[allow(clippy::large_enum_variant)]
 #[derive(Clone, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case", tag = "material")]
 pub enum MaterialType {
@@ -20,39 +35,380 @@ pub enum MaterialType {
         albedo: Vec4,
         fuzziness: f32,
     },
+    /// Glass or liquid, with `refractive_index` at the middle of the visible spectrum.
     Dielectric {
         refractive_index: f32,
+        /// Color left of white light after travelling `absorption_distance` inside the object,
+        /// which must then be closed.
+        #[serde(default = "default_transmittance")]
+        transmittance: Vec4,
+        #[serde(default = "default_absorption_distance")]
+        absorption_distance: f32,
+        /// Abbe number of the dispersion, lower for stronger dispersion, such as about 64 for
+        /// crown glass and 36 for flint glass. Without it, every wavelength bends the same way.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        abbe_number: Option<f32>,
+    },
+    /// Rough metal with a GGX microfacet distribution, described by its complex index of
+    /// refraction per RGB channel. Gold is about `eta = [0.18, 0.42, 1.37]` and
+    /// `k = [3.42, 2.35, 1.77]`.
+    Conductor {
+        eta: Vec3,
+        k: Vec3,
+        roughness: f32,
+    },
+    /// Diffuse texture under a rough dielectric coating.
+    Plastic {
+        #[serde(flatten)]
+        texture: Texture,
+        roughness: f32,
+        #[serde(default = "default_coating_index")]
+        refractive_index: f32,
+    },
+    /// Metallic-roughness material whose parameters vary across the surface. Each parameter is
+    /// a texture, a color or a single number, and scalar parameters read the red channel.
+    Principled {
+        #[serde(deserialize_with = "deserialize_parameter")]
+        base_color: Texture,
+        #[serde(
+            default = "default_metallic",
+            deserialize_with = "deserialize_parameter"
+        )]
+        metallic: Texture,
+        #[serde(deserialize_with = "deserialize_parameter")]
+        roughness: Texture,
+        #[serde(
+            default = "default_emission",
+            deserialize_with = "deserialize_parameter"
+        )]
+        emission: Texture,
+        #[serde(default = "default_emission_intensity")]
+        emission_intensity: f32,
+        /// Chance of a ray hitting the surface instead of going through it.
+        #[serde(
+            default = "default_opacity",
+            deserialize_with = "deserialize_parameter"
+        )]
+        opacity: Texture,
     },
+    /// Light source emitting `color` times `intensity`, where `color` can be textured.
     Emissive {
-        color: Vec4,
+        #[serde(deserialize_with = "deserialize_parameter")]
+        color: Texture,
         intensity: f32,
+        /// Whether the back of the surface emits too. One-sided emitters only light the side
+        /// their normal faces, which is the outside of spheres, boxes and closed meshes.
+        #[serde(default = "default_two_sided")]
+        two_sided: bool,
+        /// Material scattering the light reaching the emitter, which otherwise absorbs it.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        base: Option<Box<MaterialType>>,
+    },
+    /// Invisible surface that rays go straight through, marking the boundary of the medium of
+    /// its geometry.
+    Interface,
+    /// `base` with its shading normal read from a tangent space normal map, whose red, green
+    /// and blue channels go from -1 to 1 along the tangent, bitangent and normal.
+    NormalMap {
+        #[serde(deserialize_with = "deserialize_parameter")]
+        normal_map: Texture,
+        base: Box<MaterialType>,
+    },
+    /// `base` with its shading normal tilted by the slopes of a height texture, read from its
+    /// red channel and scaled by `scale` in world units.
+    BumpMap {
+        #[serde(deserialize_with = "deserialize_parameter")]
+        height: Texture,
+        scale: f32,
+        base: Box<MaterialType>,
     },
+    /// `base` with holes where the alpha channel of `opacity` is below `cutoff`, such as
+    /// leaves or fences. Without a cutoff, the alpha is the chance of a ray hitting the
+    /// surface instead of going through it.
+    AlphaMask {
+        #[serde(deserialize_with = "deserialize_parameter")]
+        opacity: Texture,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cutoff: Option<f32>,
+        base: Box<MaterialType>,
+    },
+    /// Blend of two materials, such as rust patches over metal, picking `second` with the
+    /// chance read from the red channel of `mask`.
+    Mix {
+        #[serde(deserialize_with = "deserialize_parameter")]
+        mask: Texture,
+        first: Box<MaterialType>,
+        second: Box<MaterialType>,
+    },
+    /// Rough dielectric coat over any `base` material, such as clear varnish over paint. The
+    /// light going through the coat to the base and back is tinted by `color`.
+    Layered {
+        #[serde(default)]
+        roughness: f32,
+        #[serde(default = "default_coating_index")]
+        refractive_index: f32,
+        #[serde(default = "default_transmittance")]
+        color: Vec4,
+        base: Box<MaterialType>,
+    },
+}
+
+/// Offset in UV units used to measure the slopes of heights without texels.
+const BUMP_DELTA: f32 = 1e-3;
+
+fn default_coating_index() -> f32 {
+    1.5
+}
+
+fn default_transmittance() -> Vec4 {
+    Vec4::ONE
+}
+
+fn default_absorption_distance() -> f32 {
+    1.0
+}
+
+fn default_metallic() -> Texture {
+    Texture::Solid { color: Vec4::ZERO }
+}
+
+fn default_emission() -> Texture {
+    Texture::Solid { color: Vec4::ZERO }
+}
+
+fn default_emission_intensity() -> f32 {
+    1.0
+}
+
+fn default_opacity() -> Texture {
+    Texture::Solid { color: Vec4::ONE }
+}
+
+fn default_two_sided() -> bool {
+    true
+}
+
+/// Material parameter written as a texture table, an RGBA color or a single number.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ParameterSettings {
+    Value(f32),
+    Color(Vec4),
+    Texture(Texture),
+}
+
+fn deserialize_parameter<'de, D>(deserializer: D) -> Result<Texture, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(match ParameterSettings::deserialize(deserializer)? {
+        ParameterSettings::Value(value) => Texture::Solid {
+            color: Vec4::splat(value),
+        },
+        ParameterSettings::Color(color) => Texture::Solid { color },
+        ParameterSettings::Texture(texture) => texture,
+    })
 }
 
 impl MaterialType {
-    pub fn emit(&self) -> Vec4 {
+    /// Radiance emitted at the hit.
+    pub fn emit(&self, trace_result: &TraceResult) -> Vec4 {
         match self {
-            MaterialType::Emissive { color, intensity } => *color * *intensity,
-            _ => Vec4::ZERO,
+            MaterialType::Principled {
+                emission,
+                emission_intensity,
+                ..
+            } => emission.sample_hit(trace_result) * *emission_intensity,
+            MaterialType::Emissive {
+                color,
+                intensity,
+                two_sided,
+                base,
+            } => {
+                let base_emission = base
+                    .as_ref()
+                    .map_or(Vec4::ZERO, |base| base.emit(trace_result));
+                if *two_sided || trace_result.front_face {
+                    color.sample_hit(trace_result) * *intensity + base_emission
+                } else {
+                    base_emission
+                }
+            }
+            MaterialType::NormalMap { base, .. }
+            | MaterialType::BumpMap { base, .. }
+            | MaterialType::AlphaMask { base, .. }
+            | MaterialType::Layered { base, .. } => base.emit(trace_result),
+            MaterialType::Mix {
+                mask,
+                first,
+                second,
+            } => first
+                .emit(trace_result)
+                .lerp(second.emit(trace_result), mix_weight(mask, trace_result)),
+            _ => self.uniform_emission().unwrap_or(Vec4::ZERO),
+        }
+    }
+
+    /// Radiance emitted the same way over the whole surface, or `None` when it varies.
+    pub fn uniform_emission(&self) -> Option<Vec4> {
+        self.emission_with(&|texture| match texture {
+            Texture::Solid { color } => Some(*color),
+            _ => None,
+        })
+    }
+
+    /// Radiance emitted on average over the surface, which weighs the emitter among the
+    /// lights. `None` when light samples could land in the holes of an alpha mask, those
+    /// emitters are only found by scattering.
+    pub fn average_emission(&self) -> Option<Vec4> {
+        if self.has_alpha_mask() {
+            return None;
+        }
+        self.emission_with(&|texture| Some(texture.average()))
+    }
+
+    /// Emission with every texture replaced by the color `texture_color` gives for it, or
+    /// `None` if it gives none.
+    fn emission_with(&self, texture_color: &impl Fn(&Texture) -> Option<Vec4>) -> Option<Vec4> {
+        match self {
+            MaterialType::Emissive {
+                color,
+                intensity,
+                base,
+                ..
+            } => {
+                let base_emission = match base {
+                    Some(base) => base.emission_with(texture_color)?,
+                    None => Vec4::ZERO,
+                };
+                Some(texture_color(color)? * *intensity + base_emission)
+            }
+            MaterialType::Principled {
+                emission,
+                emission_intensity,
+                ..
+            } => Some(texture_color(emission)? * *emission_intensity),
+            MaterialType::NormalMap { base, .. }
+            | MaterialType::BumpMap { base, .. }
+            | MaterialType::AlphaMask { base, .. }
+            | MaterialType::Layered { base, .. } => base.emission_with(texture_color),
+            MaterialType::Mix {
+                mask,
+                first,
+                second,
+            } => Some(first.emission_with(texture_color)?.lerp(
+                second.emission_with(texture_color)?,
+                texture_color(mask)?.x.clamp(0.0, 1.0),
+            )),
+            _ => Some(Vec4::ZERO),
+        }
+    }
+
+    /// Whether rays can go through some hits on the surface, which tracers then check with
+    /// [`MaterialType::is_opaque_at`].
+    pub fn has_alpha_mask(&self) -> bool {
+        match self {
+            MaterialType::AlphaMask { .. } => true,
+            MaterialType::Principled {
+                opacity: Texture::Solid { color },
+                ..
+            } => color.x < 1.0,
+            MaterialType::Principled { .. } => true,
+            MaterialType::NormalMap { base, .. }
+            | MaterialType::BumpMap { base, .. }
+            | MaterialType::Layered { base, .. } => base.has_alpha_mask(),
+            MaterialType::Mix { first, second, .. } => {
+                first.has_alpha_mask() || second.has_alpha_mask()
+            }
+            MaterialType::Emissive { base, .. } => {
+                base.as_ref().is_some_and(|base| base.has_alpha_mask())
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether the hit counts, or rays go through the surface there. Stochastic opacity is
+    /// decided at random, so the surface lets the rest of the light through on average.
+    pub fn is_opaque_at(&self, trace_result: &TraceResult) -> bool {
+        match self {
+            MaterialType::AlphaMask {
+                opacity,
+                cutoff,
+                base,
+            } => {
+                let alpha = opacity.sample_hit(trace_result).w;
+                let opaque = match cutoff {
+                    Some(cutoff) => alpha >= *cutoff,
+                    None => alpha > rand::random(),
+                };
+                opaque && base.is_opaque_at(trace_result)
+            }
+            MaterialType::Principled { opacity, .. } => {
+                opacity.sample_hit(trace_result).x > rand::random()
+            }
+            MaterialType::NormalMap { base, .. }
+            | MaterialType::BumpMap { base, .. }
+            | MaterialType::Layered { base, .. } => base.is_opaque_at(trace_result),
+            MaterialType::Mix {
+                mask,
+                first,
+                second,
+            } => {
+                if rand::random::<f32>() < mix_weight(mask, trace_result) {
+                    second.is_opaque_at(trace_result)
+                } else {
+                    first.is_opaque_at(trace_result)
+                }
+            }
+            MaterialType::Emissive { base, .. } => base
+                .as_ref()
+                .is_none_or(|base| base.is_opaque_at(trace_result)),
+            _ => true,
+        }
+    }
+
+    /// Whether the back of the surface emits as much as the front.
+    pub fn two_sided_emission(&self) -> bool {
+        match self {
+            MaterialType::Emissive { two_sided, .. } => *two_sided,
+            MaterialType::NormalMap { base, .. }
+            | MaterialType::BumpMap { base, .. }
+            | MaterialType::AlphaMask { base, .. }
+            | MaterialType::Layered { base, .. } => base.two_sided_emission(),
+            MaterialType::Mix { first, second, .. } => {
+                first.two_sided_emission() && second.two_sided_emission()
+            }
+            _ => true,
         }
     }
 
     pub fn scatter(&self, ray: &Ray, trace_result: &TraceResult) -> Option<ScatterResult> {
         match self {
             MaterialType::Lambertian { texture } => {
-                let mut scatter_dir = trace_result.normal + random_unit_vector();
+                let normal = trace_result.shading_normal;
+                let mut scatter_dir = normal + random_unit_vector();
 
-                if scatter_dir.x.abs() < 1e-8 && scatter_dir.y.abs() < 1e-8 && scatter_dir.z.abs() < 1e-8 {
-                    scatter_dir = trace_result.normal;
+                if scatter_dir.x.abs() < 1e-8
+                    && scatter_dir.y.abs() < 1e-8
+                    && scatter_dir.z.abs() < 1e-8
+                {
+                    scatter_dir = normal;
+                }
+                let scatter_dir = scatter_dir.normalize();
+
+                // Smooth shading can send the ray below the actual surface
+                if scatter_dir.dot(trace_result.normal) <= 0.0 {
+                    return None;
                 }
 
                 Some(ScatterResult {
-                    attenuation: texture.sample(trace_result.uv),
+                    attenuation: texture.sample_hit(trace_result) * trace_result.vertex_color,
                     scattered: Ray::new(trace_result.point, scatter_dir),
+                    pdf: Some(scatter_dir.dot(normal).max(0.0) / PI),
                 })
             }
             MaterialType::Metal { albedo, fuzziness } => {
-                let reflected = ray.direction.reflect(trace_result.normal);
+                let reflected = ray.direction.reflect(trace_result.shading_normal);
                 let reflected = reflected.normalize() + fuzziness * random_unit_vector();
                 let scattered = Ray::new(trace_result.point, reflected);
 
@@ -63,18 +419,40 @@ impl MaterialType {
                 Some(ScatterResult {
                     attenuation: *albedo,
                     scattered,
+                    pdf: None,
                 })
             }
-            MaterialType::Dielectric { refractive_index } => {
+            MaterialType::Dielectric {
+                refractive_index,
+                abbe_number,
+                ..
+            } => {
+                // Dispersion restricts the path to a single wavelength, weighted by its color
+                let (refractive_index, attenuation, wavelength) = match abbe_number {
+                    Some(abbe_number) => {
+                        let (wavelength, weight) = match ray.wavelength {
+                            Some(wavelength) => (wavelength, Vec3::ONE),
+                            None => sample_wavelength(),
+                        };
+                        (
+                            cauchy_index(*refractive_index, *abbe_number, wavelength),
+                            weight.extend(1.0),
+                            Some(wavelength),
+                        )
+                    }
+                    None => (*refractive_index, Vec4::ONE, ray.wavelength),
+                };
+
                 let refraction_ratio = if trace_result.front_face {
                     1.0 / refractive_index
                 } else {
-                    *refractive_index
+                    refractive_index
                 };
 
                 let unit_direction = ray.direction.normalize();
 
-                let cos_theta = (-unit_direction).dot(trace_result.normal).min(1.0);
+                let normal = trace_result.shading_normal;
+                let cos_theta = (-unit_direction).dot(normal).min(1.0);
                 let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
 
                 let cannot_refract = refraction_ratio * sin_theta > 1.0;
@@ -82,21 +460,422 @@ impl MaterialType {
                 let direction = if cannot_refract
                     || reflectance(cos_theta, refraction_ratio) > rand::random()
                 {
-                    unit_direction.reflect(trace_result.normal)
+                    unit_direction.reflect(normal)
                 } else {
-                    unit_direction.refract(trace_result.normal, refraction_ratio)
+                    unit_direction.refract(normal, refraction_ratio)
                 };
 
                 Some(ScatterResult {
-                    attenuation: Vec4::ONE,
-                    scattered: Ray::new(trace_result.point, direction),
+                    attenuation,
+                    scattered: Ray {
+                        wavelength,
+                        ..Ray::new(trace_result.point, direction)
+                    },
+                    pdf: None,
+                })
+            }
+            MaterialType::Conductor { .. }
+            | MaterialType::Plastic { .. }
+            | MaterialType::Principled { .. } => self
+                .with_microfacet(trace_result, |bsdf| sample_bsdf(bsdf, ray, trace_result))
+                .flatten(),
+            MaterialType::Emissive { base, .. } => base.as_ref()?.scatter(ray, trace_result),
+            MaterialType::Interface => Some(ScatterResult {
+                attenuation: Vec4::ONE,
+                scattered: Ray::new(trace_result.point, ray.direction),
+                pdf: None,
+            }),
+            MaterialType::NormalMap { base, .. }
+            | MaterialType::BumpMap { base, .. }
+            | MaterialType::AlphaMask { base, .. } => {
+                base.scatter(ray, &self.detailed(trace_result))
+            }
+            MaterialType::Mix {
+                mask,
+                first,
+                second,
+            } => {
+                let chosen = if rand::random::<f32>() < mix_weight(mask, trace_result) {
+                    second
+                } else {
+                    first
+                };
+                // Specular scattering keeps the weight of its material, which cancels with
+                // the chance of picking it
+                let scattered = chosen.scatter(ray, trace_result)?;
+                if scattered.pdf.is_none() {
+                    return Some(scattered);
+                }
+                self.with_blended_lobes(ray, trace_result, scattered)
+            }
+            MaterialType::Layered {
+                roughness,
+                refractive_index,
+                color,
+                base,
+            } => {
+                let frame = Frame::new(trace_result.shading_normal);
+                let wo = frame.to_local(-ray.direction.normalize());
+                // The coat is on the outside of the base
+                if !trace_result.front_face || wo.z <= 0.0 {
+                    return base.scatter(ray, trace_result);
+                }
+
+                let probability = coat_probability(wo, *refractive_index);
+                if rand::random::<f32>() < probability {
+                    let coating = Coating {
+                        alpha: roughness_to_alpha(*roughness),
+                        eta: *refractive_index,
+                    };
+                    let scattered = sample_bsdf(&coating, ray, trace_result)?;
+                    return self.with_blended_lobes(ray, trace_result, scattered);
+                }
+
+                let scattered = base.scatter(ray, trace_result)?;
+                if scattered.pdf.is_some() {
+                    return self.with_blended_lobes(ray, trace_result, scattered);
+                }
+                let wi = frame.to_local(scattered.scattered.direction.normalize());
+                let transmission = coat_transmission(wo, wi, *refractive_index, *color);
+                Some(ScatterResult {
+                    attenuation: scattered.attenuation * transmission / (1.0 - probability),
+                    ..scattered
+                })
+            }
+        }
+    }
+
+    /// Replaces the weight of a direction sampled from one lobe of a blended material with
+    /// the weight of the whole material, since every lobe could have sampled it.
+    fn with_blended_lobes(
+        &self,
+        ray: &Ray,
+        trace_result: &TraceResult,
+        scattered: ScatterResult,
+    ) -> Option<ScatterResult> {
+        let (value, pdf) = self.eval(ray, trace_result, scattered.scattered.direction)?;
+        Some(ScatterResult {
+            attenuation: value / pdf,
+            pdf: Some(pdf),
+            ..scattered
+        })
+    }
+
+    /// Whether [`MaterialType::eval`] can scatter towards some directions, so sampling lights
+    /// is worth it. Specular materials only scatter where they choose, and bare emitters don't.
+    pub fn has_bsdf(&self) -> bool {
+        match self {
+            MaterialType::Lambertian { .. }
+            | MaterialType::Conductor { .. }
+            | MaterialType::Plastic { .. }
+            | MaterialType::Principled { .. }
+            | MaterialType::Layered { .. } => true,
+            MaterialType::NormalMap { base, .. }
+            | MaterialType::BumpMap { base, .. }
+            | MaterialType::AlphaMask { base, .. } => base.has_bsdf(),
+            MaterialType::Emissive { base, .. } => {
+                base.as_ref().is_some_and(|base| base.has_bsdf())
+            }
+            MaterialType::Mix { first, second, .. } => first.has_bsdf() || second.has_bsdf(),
+            MaterialType::Metal { .. }
+            | MaterialType::Dielectric { .. }
+            | MaterialType::Interface => false,
+        }
+    }
+
+    /// Evaluates the scattering of `ray` towards `direction` (normalized), returning the BSDF times
+    /// the cosine term and the density with which [`MaterialType::scatter`] samples it.
+    /// Specular materials return `None`, since they can't scatter towards arbitrary directions.
+    pub fn eval(
+        &self,
+        ray: &Ray,
+        trace_result: &TraceResult,
+        direction: Vec3,
+    ) -> Option<(Vec4, f32)> {
+        match self {
+            MaterialType::Lambertian { texture } => {
+                let cos_theta = direction.dot(trace_result.shading_normal);
+                if cos_theta <= 0.0 || direction.dot(trace_result.normal) <= 0.0 {
+                    return None;
+                }
+
+                let albedo = texture.sample_hit(trace_result) * trace_result.vertex_color;
+                Some((albedo * cos_theta / PI, cos_theta / PI))
+            }
+            MaterialType::Conductor { .. }
+            | MaterialType::Plastic { .. }
+            | MaterialType::Principled { .. } => self
+                .with_microfacet(trace_result, |bsdf| {
+                    eval_bsdf(bsdf, ray, trace_result, direction)
                 })
+                .flatten(),
+            MaterialType::NormalMap { base, .. }
+            | MaterialType::BumpMap { base, .. }
+            | MaterialType::AlphaMask { base, .. } => {
+                base.eval(ray, &self.detailed(trace_result), direction)
+            }
+            MaterialType::Emissive { base, .. } => {
+                base.as_ref()?.eval(ray, trace_result, direction)
+            }
+            MaterialType::Mix {
+                mask,
+                first,
+                second,
+            } => {
+                let weight = mix_weight(mask, trace_result);
+                blend_lobes([
+                    (
+                        first.eval(ray, trace_result, direction),
+                        Vec4::splat(1.0 - weight),
+                        1.0 - weight,
+                    ),
+                    (
+                        second.eval(ray, trace_result, direction),
+                        Vec4::splat(weight),
+                        weight,
+                    ),
+                ])
+            }
+            MaterialType::Layered {
+                roughness,
+                refractive_index,
+                color,
+                base,
+            } => {
+                let frame = Frame::new(trace_result.shading_normal);
+                let wo = frame.to_local(-ray.direction.normalize());
+                if !trace_result.front_face || wo.z <= 0.0 {
+                    return base.eval(ray, trace_result, direction);
+                }
+
+                let coating = Coating {
+                    alpha: roughness_to_alpha(*roughness),
+                    eta: *refractive_index,
+                };
+                let probability = coat_probability(wo, *refractive_index);
+                let transmission =
+                    coat_transmission(wo, frame.to_local(direction), *refractive_index, *color);
+                blend_lobes([
+                    (
+                        eval_bsdf(&coating, ray, trace_result, direction),
+                        Vec4::ONE,
+                        probability,
+                    ),
+                    (
+                        base.eval(ray, trace_result, direction),
+                        transmission,
+                        1.0 - probability,
+                    ),
+                ])
             }
-            MaterialType::Emissive { .. } => None,
+            MaterialType::Metal { .. }
+            | MaterialType::Dielectric { .. }
+            | MaterialType::Interface => None,
+        }
+    }
+
+    /// Medium the material fills its geometry with, absorbing the light going through it.
+    pub fn interior_medium(&self) -> Option<Medium> {
+        match self {
+            MaterialType::Dielectric {
+                transmittance,
+                absorption_distance,
+                ..
+            } if transmittance.truncate() != Vec3::ONE => Some(Medium {
+                // Beer-Lambert law, keeping black channels finite
+                absorption: -transmittance.truncate().max(Vec3::splat(1e-6)).map(f32::ln)
+                    / *absorption_distance,
+                scattering: Vec3::ZERO,
+                asymmetry: 0.0,
+            }),
+            MaterialType::NormalMap { base, .. }
+            | MaterialType::BumpMap { base, .. }
+            | MaterialType::AlphaMask { base, .. }
+            | MaterialType::Layered { base, .. } => base.interior_medium(),
+            MaterialType::Emissive { base, .. } => base.as_ref()?.interior_medium(),
+            _ => None,
+        }
+    }
+
+    /// The hit with the shading normal given by the normal or bump map of the material.
+    fn detailed(&self, trace_result: &TraceResult) -> TraceResult {
+        let normal = trace_result.shading_normal;
+        let detailed_normal = match self {
+            MaterialType::NormalMap { normal_map, .. } => {
+                let value = normal_map.sample_hit(trace_result).truncate() * 2.0 - Vec3::ONE;
+                let (tangent, bitangent) = tangent_frame(trace_result);
+                tangent * value.x + bitangent * value.y + normal * value.z
+            }
+            MaterialType::BumpMap { height, scale, .. } => {
+                let (u, v) = trace_result.uv;
+                let (du, dv) = height.texel_size().unwrap_or((BUMP_DELTA, BUMP_DELTA));
+                // Step the point along with the UV so solid textures get a slope as well
+                let point = trace_result.point;
+                let (dpdu, dpdv) = (trace_result.tangent, trace_result.bitangent);
+                let height =
+                    |uv, point| height.sample(uv, point, trace_result.uv_derivatives).x * scale;
+                let slope_u = (height((u + du, v), point + dpdu * du)
+                    - height((u - du, v), point - dpdu * du))
+                    / (2.0 * du);
+                let slope_v = (height((u, v + dv), point + dpdv * dv)
+                    - height((u, v - dv), point - dpdv * dv))
+                    / (2.0 * dv);
+
+                // Normal of the surface displaced along the normal by the height
+                let tangent = trace_result.tangent + slope_u * normal;
+                let bitangent = trace_result.bitangent + slope_v * normal;
+                let bumped = tangent.cross(bitangent);
+                if bumped.dot(normal) < 0.0 {
+                    -bumped
+                } else {
+                    bumped
+                }
+            }
+            _ => normal,
+        };
+
+        TraceResult {
+            shading_normal: detailed_normal.try_normalize().unwrap_or(normal),
+            ..trace_result.clone()
+        }
+    }
+
+    /// Calls `f` with the microfacet BSDF of the material at the hit, if it has one.
+    fn with_microfacet<R>(
+        &self,
+        trace_result: &TraceResult,
+        f: impl FnOnce(&dyn Bsdf) -> R,
+    ) -> Option<R> {
+        match self {
+            MaterialType::Conductor { eta, k, roughness } => Some(f(&Conductor {
+                alpha: roughness_to_alpha(*roughness),
+                eta: *eta,
+                k: *k,
+            })),
+            MaterialType::Plastic {
+                texture,
+                roughness,
+                refractive_index,
+            } => {
+                let albedo = texture.sample_hit(trace_result) * trace_result.vertex_color;
+                Some(f(&Plastic {
+                    alpha: roughness_to_alpha(*roughness),
+                    eta: *refractive_index,
+                    albedo: albedo.truncate(),
+                }))
+            }
+            MaterialType::Principled {
+                base_color,
+                metallic,
+                roughness,
+                ..
+            } => {
+                let base_color = base_color.sample_hit(trace_result) * trace_result.vertex_color;
+                Some(f(&Principled {
+                    alpha: roughness_to_alpha(roughness.sample_hit(trace_result).x.clamp(0.0, 1.0)),
+                    base_color: base_color.truncate(),
+                    metallic: metallic.sample_hit(trace_result).x.clamp(0.0, 1.0),
+                }))
+            }
+            _ => None,
         }
     }
 }
 
+/// Samples a BSDF in the shading frame of the hit.
+fn sample_bsdf(bsdf: &dyn Bsdf, ray: &Ray, trace_result: &TraceResult) -> Option<ScatterResult> {
+    let frame = Frame::new(trace_result.shading_normal);
+    let wo = frame.to_local(-ray.direction.normalize());
+    let wi = bsdf.sample(wo)?;
+
+    let direction = frame.to_world(wi);
+    // Smooth shading can send the ray below the actual surface
+    if direction.dot(trace_result.normal) <= 0.0 {
+        return None;
+    }
+
+    let pdf = bsdf.pdf(wo, wi);
+    if pdf <= 0.0 {
+        return None;
+    }
+    Some(ScatterResult {
+        attenuation: (bsdf.eval(wo, wi) / pdf).extend(1.0),
+        scattered: Ray::new(trace_result.point, direction),
+        pdf: Some(pdf),
+    })
+}
+
+fn eval_bsdf(
+    bsdf: &dyn Bsdf,
+    ray: &Ray,
+    trace_result: &TraceResult,
+    direction: Vec3,
+) -> Option<(Vec4, f32)> {
+    if direction.dot(trace_result.normal) <= 0.0 {
+        return None;
+    }
+
+    let frame = Frame::new(trace_result.shading_normal);
+    let wo = frame.to_local(-ray.direction.normalize());
+    let wi = frame.to_local(direction);
+    let pdf = bsdf.pdf(wo, wi);
+    if pdf <= 0.0 {
+        return None;
+    }
+    Some((bsdf.eval(wo, wi).extend(1.0), pdf))
+}
+
+/// Evaluation of a lobe of a blended material, with its weight in the material and its chance
+/// of being sampled.
+type Lobe = (Option<(Vec4, f32)>, Vec4, f32);
+
+/// Combines the evaluations of the two lobes of a blended material.
+fn blend_lobes(lobes: [Lobe; 2]) -> Option<(Vec4, f32)> {
+    let mut value = Vec4::ZERO;
+    let mut pdf = 0.0;
+    for (lobe, weight, probability) in lobes {
+        if let Some((lobe_value, lobe_pdf)) = lobe {
+            value += lobe_value * weight;
+            pdf += lobe_pdf * probability;
+        }
+    }
+    (pdf > 0.0).then_some((value, pdf))
+}
+
+fn mix_weight(mask: &Texture, trace_result: &TraceResult) -> f32 {
+    mask.sample_hit(trace_result).x.clamp(0.0, 1.0)
+}
+
+/// Chance of sampling the coat of a layered material rather than its base.
+fn coat_probability(wo: Vec3, refractive_index: f32) -> f32 {
+    reflectance(wo.z, refractive_index).clamp(0.1, 0.9)
+}
+
+/// Fraction of light going through the coat of a layered material to its base and back out.
+fn coat_transmission(wo: Vec3, wi: Vec3, refractive_index: f32, color: Vec4) -> Vec4 {
+    let transmission = (1.0 - reflectance(wo.z, refractive_index))
+        * (1.0 - reflectance(wi.z.abs(), refractive_index));
+    color * transmission
+}
+
+/// Orthonormal tangent and bitangent around the shading normal, following the directions of
+/// the texture coordinates.
+fn tangent_frame(trace_result: &TraceResult) -> (Vec3, Vec3) {
+    let normal = trace_result.shading_normal;
+    let tangent = trace_result.tangent - normal * normal.dot(trace_result.tangent);
+    let Some(tangent) = tangent.try_normalize() else {
+        return normal.any_orthonormal_pair();
+    };
+
+    // Mirrored texture coordinates flip the bitangent
+    let bitangent = normal.cross(tangent);
+    if bitangent.dot(trace_result.bitangent) < 0.0 {
+        (tangent, -bitangent)
+    } else {
+        (tangent, bitangent)
+    }
+}
+
 fn reflectance(cosine: f32, refractive_index: f32) -> f32 {
     let r0 = (1.0 - refractive_index) / (1.0 + refractive_index);
     let r0 = r0 * r0;
@@ -115,10 +894,13 @@ fn random_unit_vector() -> Vec3 {
 }
 
 pub mod texture {
-    use glam::Vec4;
+    use glam::{Affine2, DVec4, UVec2, Vec2, Vec3, Vec4};
     use image::Rgba32FImage;
     use serde::{Deserialize, Serialize};
 
+    use super::noise;
+    use crate::raytracer::tracer::TraceResult;
+
     #[derive(Clone, Serialize, Deserialize)]
     #[serde(rename_all = "snake_case", tag = "texture")]
     pub enum Texture {
@@ -130,17 +912,134 @@ pub mod texture {
             color2: Vec4,
             scale: f32,
         },
-        Image {
-            #[serde(
-                deserialize_with = "crate::raytracer::loader::deserialize_image",
-                serialize_with = "crate::raytracer::loader::serialize_image"
-            )]
-            image: Rgba32FImage,
+        #[serde(
+            deserialize_with = "crate::raytracer::loader::deserialize_image_texture",
+            serialize_with = "crate::raytracer::loader::serialize_image_texture"
+        )]
+        Image(ImageTexture),
+        /// Perlin noise with features about `scale` wide, summed over `octaves` as fBm.
+        Noise {
+            color1: Vec4,
+            color2: Vec4,
+            scale: f32,
+            #[serde(default = "default_octaves")]
+            octaves: u32,
+            #[serde(default)]
+            space: TextureSpace,
+        },
+        /// Veins along the x axis, `scale` apart and bent by `turbulence`.
+        Marble {
+            color1: Vec4,
+            color2: Vec4,
+            scale: f32,
+            #[serde(default = "default_turbulence")]
+            turbulence: f32,
+            #[serde(default = "default_octaves")]
+            octaves: u32,
+            #[serde(default)]
+            space: TextureSpace,
+        },
+        /// Growth rings around the y axis, `scale` apart and warped by `turbulence`.
+        Wood {
+            color1: Vec4,
+            color2: Vec4,
+            scale: f32,
+            #[serde(default = "default_ring_turbulence")]
+            turbulence: f32,
+            #[serde(default)]
+            space: TextureSpace,
+        },
+        /// Goes from `color1` at `start` to `color2` at `end`, constant across the line between.
+        LinearGradient {
+            color1: Vec4,
+            color2: Vec4,
+            start: Vec3,
+            end: Vec3,
+            #[serde(default)]
+            space: TextureSpace,
+        },
+        /// Goes from `color1` at `center` to `color2` at `radius` away from it.
+        RadialGradient {
+            color1: Vec4,
+            color2: Vec4,
+            center: Vec3,
+            radius: f32,
+            #[serde(default)]
+            space: TextureSpace,
         },
     }
 
+    /// Coordinates procedural textures are evaluated at.
+    #[derive(Clone, Copy, Default, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum TextureSpace {
+        /// Texture coordinates of the hit, as (u, v, 0), following the surface.
+        Uv,
+        /// Position of the hit in the world, carving the object out of a solid block.
+        #[default]
+        World,
+    }
+
+    impl TextureSpace {
+        fn coordinates(self, (u, v): (f32, f32), point: Vec3) -> Vec3 {
+            match self {
+                TextureSpace::Uv => Vec3::new(u, v, 0.0),
+                TextureSpace::World => point,
+            }
+        }
+    }
+
+    fn default_octaves() -> u32 {
+        6
+    }
+
+    fn default_turbulence() -> f32 {
+        5.0
+    }
+
+    fn default_ring_turbulence() -> f32 {
+        0.5
+    }
+
     impl Texture {
-        pub fn sample(&self, (u, v): (f32, f32)) -> Vec4 {
+        /// Size of a texel in UV units, for textures made of texels.
+        pub fn texel_size(&self) -> Option<(f32, f32)> {
+            match self {
+                Texture::Image(image) => Some(image.texel_size()),
+                _ => None,
+            }
+        }
+
+        /// Color averaged over the texture, roughly for procedural textures, whose two colors
+        /// are taken as equally common.
+        pub fn average(&self) -> Vec4 {
+            match self {
+                Texture::Solid { color } => *color,
+                Texture::Image(image) => image.average(),
+                Texture::Checker { color1, color2, .. }
+                | Texture::Noise { color1, color2, .. }
+                | Texture::Marble { color1, color2, .. }
+                | Texture::Wood { color1, color2, .. }
+                | Texture::LinearGradient { color1, color2, .. }
+                | Texture::RadialGradient { color1, color2, .. } => 0.5 * (color1 + color2),
+            }
+        }
+
+        /// Color at the hit.
+        pub fn sample_hit(&self, hit: &TraceResult) -> Vec4 {
+            self.sample(hit.uv, hit.point, hit.uv_derivatives)
+        }
+
+        /// Color at texture coordinates `uv` and world position `point` of a hit. Only
+        /// procedural textures look at the position, and only image textures at the change of
+        /// the coordinates across a pixel, `uv_derivatives`, to blur away details smaller than it.
+        pub fn sample(
+            &self,
+            uv: (f32, f32),
+            point: Vec3,
+            uv_derivatives: Option<(Vec2, Vec2)>,
+        ) -> Vec4 {
+            let (u, v) = uv;
             match self {
                 Texture::Solid { color } => *color,
                 Texture::Checker {
@@ -153,12 +1052,435 @@ pub mod texture {
 
                     if (x + y) % 2 == 0 { *color1 } else { *color2 }
                 }
-                Texture::Image { image } => {
-                    let pixel = image::imageops::sample_bilinear(image, u, 1.0 - v)
-                        .unwrap_or([0.0, 0.0, 0.0, 0.0].into());
-                    Vec4::new(pixel[0], pixel[1], pixel[2], pixel[3])
+                Texture::Image(image) => image.sample(uv, uv_derivatives),
+                Texture::Noise {
+                    color1,
+                    color2,
+                    scale,
+                    octaves,
+                    space,
+                } => {
+                    let p = space.coordinates(uv, point) / scale;
+                    let t = 0.5 + 0.5 * noise::fbm(p, *octaves);
+                    color1.lerp(*color2, t.clamp(0.0, 1.0))
+                }
+                Texture::Marble {
+                    color1,
+                    color2,
+                    scale,
+                    turbulence,
+                    octaves,
+                    space,
+                } => {
+                    let p = space.coordinates(uv, point) / scale;
+                    let phase = p.x + turbulence * noise::turbulence(p, *octaves);
+                    let t = 0.5 + 0.5 * (std::f32::consts::PI * phase).sin();
+                    color1.lerp(*color2, t)
+                }
+                Texture::Wood {
+                    color1,
+                    color2,
+                    scale,
+                    turbulence,
+                    space,
+                } => {
+                    let p = space.coordinates(uv, point) / scale;
+                    let radius = p.x.hypot(p.z) + turbulence * noise::fbm(p * 0.5, 2);
+                    // Sharp dark edge at the end of each ring, fading out across it
+                    let t = radius.rem_euclid(1.0).powi(3);
+                    color1.lerp(*color2, t)
+                }
+                Texture::LinearGradient {
+                    color1,
+                    color2,
+                    start,
+                    end,
+                    space,
+                } => {
+                    let p = space.coordinates(uv, point);
+                    let axis = *end - *start;
+                    let t = (p - *start).dot(axis) / axis.length_squared().max(f32::EPSILON);
+                    color1.lerp(*color2, t.clamp(0.0, 1.0))
+                }
+                Texture::RadialGradient {
+                    color1,
+                    color2,
+                    center,
+                    radius,
+                    space,
+                } => {
+                    let p = space.coordinates(uv, point);
+                    let t = p.distance(*center) / radius;
+                    color1.lerp(*color2, t.clamp(0.0, 1.0))
+                }
+            }
+        }
+    }
+
+    /// Image with its own texture coordinate transform, wrap mode and filter, and a mip pyramid
+    /// to look it up from afar.
+    #[derive(Clone)]
+    pub struct ImageTexture {
+        /// The image then copies of it halved in size down to a single texel.
+        levels: Vec<Rgba32FImage>,
+        sampler: Sampler,
+        /// From the texture coordinates of hits to the ones of the image.
+        uv_transform: Affine2,
+        /// Mean color of the image, which the filtered mip levels only approximate.
+        average: Vec4,
+    }
+
+    /// How an image texture is looked up.
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct Sampler {
+        #[serde(default)]
+        pub wrap: WrapMode,
+        /// Wrap mode along v when it differs from `wrap`, which then only applies along u.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub wrap_v: Option<WrapMode>,
+        #[serde(default)]
+        pub filter: Filter,
+        /// Number of times the image repeats across the texture coordinates of the surface.
+        #[serde(default = "default_uv_scale")]
+        pub uv_scale: Vec2,
+        #[serde(default)]
+        pub uv_offset: Vec2,
+        /// Counter-clockwise rotation, in degrees, applied after the scale and before the offset.
+        #[serde(default)]
+        pub uv_rotation: f32,
+    }
+
+    impl Default for Sampler {
+        fn default() -> Self {
+            Sampler {
+                wrap: WrapMode::default(),
+                wrap_v: None,
+                filter: Filter::default(),
+                uv_scale: default_uv_scale(),
+                uv_offset: Vec2::ZERO,
+                uv_rotation: 0.0,
+            }
+        }
+    }
+
+    fn default_uv_scale() -> Vec2 {
+        Vec2::ONE
+    }
+
+    /// What lies outside of the image.
+    #[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum WrapMode {
+        /// The image tiles the plane.
+        #[default]
+        Repeat,
+        /// The image tiles the plane, every other copy flipped so that the edges match.
+        Mirror,
+        /// The texels on the edges stretch out.
+        Clamp,
+    }
+
+    impl WrapMode {
+        /// Brings the texel index `i` into an image `size` texels wide.
+        fn wrap(self, i: i32, size: u32) -> u32 {
+            let size = size as i32;
+            let i = match self {
+                WrapMode::Repeat => i.rem_euclid(size),
+                WrapMode::Mirror => {
+                    let i = i.rem_euclid(2 * size);
+                    if i < size { i } else { 2 * size - 1 - i }
+                }
+                WrapMode::Clamp => i.clamp(0, size - 1),
+            };
+            i as u32
+        }
+    }
+
+    /// How texels are blended between their centers.
+    #[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum Filter {
+        /// The closest texel, for pixel art.
+        Nearest,
+        /// Linear between the 2x2 closest texels.
+        #[default]
+        Bilinear,
+        /// Catmull-Rom spline through the 4x4 closest texels, sharper when magnified.
+        Bicubic,
+    }
+
+    /// Encoding of the color channels of an image file.
+    #[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum ColorSpace {
+        /// Values are used as stored, as for normal maps, heights, masks and HDR images.
+        #[default]
+        Linear,
+        /// Gamma encoded, as most color images painted or photographed are.
+        Srgb,
+    }
+
+    impl ColorSpace {
+        /// Converts the color channels of `image` from this color space to linear values.
+        /// Alpha is always linear.
+        pub fn decode(self, image: &mut Rgba32FImage) {
+            if let ColorSpace::Srgb = self {
+                for pixel in image.pixels_mut() {
+                    for c in &mut pixel.0[..3] {
+                        *c = self.decode_value(*c);
+                    }
+                }
+            }
+        }
+
+        /// Converts a single color channel value, from 0 to 1, to a linear value.
+        pub fn decode_value(self, c: f32) -> f32 {
+            match self {
+                ColorSpace::Linear => c,
+                ColorSpace::Srgb if c <= 0.04045 => c / 12.92,
+                ColorSpace::Srgb => ((c + 0.055) / 1.055).powf(2.4),
+            }
+        }
+    }
+
+    /// Most samples taken along the long axis of a stretched footprint, which sets how blurry
+    /// textures get at grazing angles.
+    const MAX_ANISOTROPY: f32 = 8.0;
+
+    impl ImageTexture {
+        pub fn new(image: Rgba32FImage, sampler: Sampler) -> Self {
+            let uv_transform = Affine2::from_scale_angle_translation(
+                sampler.uv_scale,
+                sampler.uv_rotation.to_radians(),
+                sampler.uv_offset,
+            );
+
+            let texels = f64::from(image.width()) * f64::from(image.height());
+            let sum = image.pixels().fold(DVec4::ZERO, |sum, pixel| {
+                sum + Vec4::from(pixel.0).as_dvec4()
+            });
+            let average = (sum / texels).as_vec4();
+
+            let mut levels = vec![image];
+            while let Some(level) = levels
+                .last()
+                .filter(|level| level.width() > 1 || level.height() > 1)
+            {
+                let (width, height) = level.dimensions();
+                let halved = image::imageops::resize(
+                    level,
+                    (width / 2).max(1),
+                    (height / 2).max(1),
+                    image::imageops::FilterType::Triangle,
+                );
+                levels.push(halved);
+            }
+
+            ImageTexture {
+                levels,
+                sampler,
+                uv_transform,
+                average,
+            }
+        }
+
+        pub fn sampler(&self) -> &Sampler {
+            &self.sampler
+        }
+
+        /// Color averaged over the whole image.
+        pub fn average(&self) -> Vec4 {
+            self.average
+        }
+
+        /// Size of a texel in the texture coordinates of the surface.
+        pub fn texel_size(&self) -> (f32, f32) {
+            let scale = self.sampler.uv_scale.abs().max(Vec2::splat(f32::EPSILON));
+            let (width, height) = self.levels[0].dimensions();
+            (
+                1.0 / (width as f32 * scale.x),
+                1.0 / (height as f32 * scale.y),
+            )
+        }
+
+        /// Color at `uv`, averaged over the pixel footprint given by `uv_derivatives` if any.
+        /// The footprint is an ellipse: the mip level matches its short axis, and a few samples
+        /// along its long axis cover the rest.
+        pub fn sample(&self, uv: (f32, f32), uv_derivatives: Option<(Vec2, Vec2)>) -> Vec4 {
+            let uv = self.uv_transform.transform_point2(uv.into());
+            let Some((duv_dx, duv_dy)) = uv_derivatives else {
+                return self.sample_level(0, uv);
+            };
+
+            let size = UVec2::from(self.levels[0].dimensions()).as_vec2();
+            let (dx, dy) = (
+                self.uv_transform.matrix2 * duv_dx,
+                self.uv_transform.matrix2 * duv_dy,
+            );
+            let (major, minor) = if (dx * size).length_squared() >= (dy * size).length_squared() {
+                (dx, dy)
+            } else {
+                (dy, dx)
+            };
+            let major_texels = (major * size).length();
+            let minor_texels = (minor * size)
+                .length()
+                .max(major_texels / MAX_ANISOTROPY)
+                .max(f32::MIN_POSITIVE);
+
+            let level = minor_texels.log2();
+            let taps = (major_texels / minor_texels)
+                .ceil()
+                .clamp(1.0, MAX_ANISOTROPY) as u32;
+            let color: Vec4 = (0..taps)
+                .map(|i| {
+                    let offset = (i as f32 + 0.5) / taps as f32 - 0.5;
+                    self.sample_between_levels(level, uv + major * offset)
+                })
+                .sum();
+            color / taps as f32
+        }
+
+        /// Blends the two mip levels around the fractional `level`, or takes the closest one
+        /// with nearest filtering.
+        fn sample_between_levels(&self, level: f32, uv: Vec2) -> Vec4 {
+            let level = level.clamp(0.0, (self.levels.len() - 1) as f32);
+            if let Filter::Nearest = self.sampler.filter {
+                return self.sample_level(level.round() as usize, uv);
+            }
+
+            let lower = level.floor();
+            let t = level - lower;
+            let color = self.sample_level(lower as usize, uv);
+            if t > 0.0 {
+                color.lerp(self.sample_level(lower as usize + 1, uv), t)
+            } else {
+                color
+            }
+        }
+
+        fn sample_level(&self, level: usize, uv: Vec2) -> Vec4 {
+            // Rows go down the image while v goes up, and texel centers are half a texel in
+            let (width, height) = self.levels[level].dimensions();
+            let x = uv.x * width as f32 - 0.5;
+            let y = (1.0 - uv.y) * height as f32 - 0.5;
+            let (x0, y0) = (x.floor(), y.floor());
+            let (tx, ty) = (x - x0, y - y0);
+            let (x0, y0) = (x0 as i32, y0 as i32);
+            let texel = |x, y| self.texel(level, x, y);
+
+            match self.sampler.filter {
+                Filter::Nearest => texel(x.round() as i32, y.round() as i32),
+                Filter::Bilinear => {
+                    let top = texel(x0, y0).lerp(texel(x0 + 1, y0), tx);
+                    let bottom = texel(x0, y0 + 1).lerp(texel(x0 + 1, y0 + 1), tx);
+                    top.lerp(bottom, ty)
+                }
+                Filter::Bicubic => {
+                    let (weights_x, weights_y) = (catmull_rom(tx), catmull_rom(ty));
+                    let mut color = Vec4::ZERO;
+                    for (j, weight_y) in (y0 - 1..).zip(weights_y) {
+                        for (i, weight_x) in (x0 - 1..).zip(weights_x) {
+                            color += texel(i, j) * weight_x * weight_y;
+                        }
+                    }
+                    // The spline overshoots next to sharp edges
+                    color.max(Vec4::ZERO)
                 }
             }
         }
+
+        fn texel(&self, level: usize, x: i32, y: i32) -> Vec4 {
+            let image = &self.levels[level];
+            let (width, height) = image.dimensions();
+            let wrap_u = self.sampler.wrap;
+            let wrap_v = self.sampler.wrap_v.unwrap_or(wrap_u);
+            let pixel = image.get_pixel(wrap_u.wrap(x, width), wrap_v.wrap(y, height));
+            Vec4::from(pixel.0)
+        }
+    }
+
+    /// Weights of the 4 texels around a point `t` of the way between the middle two.
+    fn catmull_rom(t: f32) -> [f32; 4] {
+        [
+            ((-0.5 * t + 1.0) * t - 0.5) * t,
+            (1.5 * t - 2.5) * t * t + 1.0,
+            ((-1.5 * t + 2.0) * t + 0.5) * t,
+            (0.5 * t - 0.5) * t * t,
+        ]
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::{Filter, ImageTexture, Sampler, WrapMode};
+        use glam::{Affine2, Vec2, Vec4};
+        use image::{Rgba, Rgba32FImage};
+
+        #[test]
+        fn wrap_modes() {
+            let size = 4;
+            let wrapped =
+                |mode: WrapMode| [-1, size, 2 * size + 1].map(|i| mode.wrap(i, size as u32));
+            assert_eq!(wrapped(WrapMode::Repeat), [3, 0, 1]);
+            assert_eq!(wrapped(WrapMode::Mirror), [0, 3, 1]);
+            assert_eq!(wrapped(WrapMode::Clamp), [0, 3, 3]);
+        }
+
+        #[test]
+        fn average_of_the_image() {
+            let mut image = Rgba32FImage::from_pixel(3, 1, Rgba([0.0; 4]));
+            image.put_pixel(2, 0, Rgba([1.0, 0.5, 0.25, 1.0]));
+            let texture = ImageTexture::new(image, Sampler::default());
+            let expected = Vec4::new(1.0, 0.5, 0.25, 1.0) / 3.0;
+            assert!(texture.average().abs_diff_eq(expected, 1e-6));
+        }
+
+        /// 4x4 texture whose flat mip levels hold their own index, to tell which get sampled.
+        fn numbered_levels(filter: Filter) -> ImageTexture {
+            let levels = [4, 2, 1]
+                .into_iter()
+                .enumerate()
+                .map(|(i, size)| Rgba32FImage::from_pixel(size, size, Rgba([i as f32; 4])))
+                .collect();
+            ImageTexture {
+                levels,
+                sampler: Sampler {
+                    filter,
+                    ..Sampler::default()
+                },
+                uv_transform: Affine2::IDENTITY,
+                average: Vec4::ZERO,
+            }
+        }
+
+        /// Level sampled for a pixel footprint spanning `texels` along u and v.
+        fn level_for(texture: &ImageTexture, texels: Vec2) -> f32 {
+            let derivatives = (
+                Vec2::new(texels.x / 4.0, 0.0),
+                Vec2::new(0.0, texels.y / 4.0),
+            );
+            texture.sample((0.5, 0.5), Some(derivatives)).x
+        }
+
+        #[test]
+        fn mip_level_follows_the_footprint() {
+            let texture = numbered_levels(Filter::Bilinear);
+            for (texels, level) in [(1.0, 0.0), (2.0, 1.0), (2f32.sqrt(), 0.5), (4.0, 2.0)] {
+                let sampled = level_for(&texture, Vec2::splat(texels));
+                assert!((sampled - level).abs() < 1e-5, "{texels} texels: {sampled}");
+            }
+
+            // Past the ends of the pyramid
+            assert_eq!(level_for(&texture, Vec2::splat(0.25)), 0.0);
+            assert_eq!(level_for(&texture, Vec2::splat(64.0)), 2.0);
+
+            // Long footprints only blur down to 1/MAX_ANISOTROPY of their length
+            assert_eq!(level_for(&texture, Vec2::new(4.0, 1.0)), 0.0);
+            assert_eq!(level_for(&texture, Vec2::new(16.0, 1.0)), 1.0);
+
+            let nearest = numbered_levels(Filter::Nearest);
+            assert_eq!(level_for(&nearest, Vec2::splat(2f32.powf(1.4))), 1.0);
+            assert_eq!(level_for(&nearest, Vec2::splat(2f32.powf(1.6))), 2.0);
+        }
     }
 }
@@ -0,0 +1,450 @@
+//! Microfacet BSDFs with the GGX distribution and height-correlated Smith masking.
+//!
+//! Everything works in a local shading frame where the normal is +Z, and both `wo` (towards
+//! the viewer) and `wi` (towards the light) point away from the surface.
+
+use crate::raytracer::material::reflectance;
+use glam::Vec3;
+use std::f32::consts::PI;
+
+/// Smallest GGX alpha, so perfectly smooth surfaces keep a finite density.
+const MIN_ALPHA: f32 = 1e-3;
+
+pub trait Bsdf {
+    /// BSDF times the cosine of `wi` with the normal.
+    fn eval(&self, wo: Vec3, wi: Vec3) -> Vec3;
+
+    /// Samples `wi` with the density given by [`Bsdf::pdf`].
+    fn sample(&self, wo: Vec3) -> Option<Vec3>;
+
+    /// Solid angle density of sampling `wi` when looking from `wo`.
+    fn pdf(&self, wo: Vec3, wi: Vec3) -> f32;
+}
+
+/// Orthonormal basis around the shading normal.
+pub struct Frame {
+    tangent: Vec3,
+    bitangent: Vec3,
+    normal: Vec3,
+}
+
+impl Frame {
+    pub fn new(normal: Vec3) -> Self {
+        let (tangent, bitangent) = normal.any_orthonormal_pair();
+        Self {
+            tangent,
+            bitangent,
+            normal,
+        }
+    }
+
+    pub fn to_local(&self, vector: Vec3) -> Vec3 {
+        Vec3::new(
+            vector.dot(self.tangent),
+            vector.dot(self.bitangent),
+            vector.dot(self.normal),
+        )
+    }
+
+    pub fn to_world(&self, vector: Vec3) -> Vec3 {
+        self.tangent * vector.x + self.bitangent * vector.y + self.normal * vector.z
+    }
+}
+
+/// Perceptual roughness in [0, 1] to the alpha of the GGX distribution.
+pub fn roughness_to_alpha(roughness: f32) -> f32 {
+    (roughness * roughness).max(MIN_ALPHA)
+}
+
+/// Metal described by its complex index of refraction `eta + i k`, per RGB channel.
+pub struct Conductor {
+    pub alpha: f32,
+    pub eta: Vec3,
+    pub k: Vec3,
+}
+
+impl Bsdf for Conductor {
+    fn eval(&self, wo: Vec3, wi: Vec3) -> Vec3 {
+        let Some(half) = half_vector(wo, wi) else {
+            return Vec3::ZERO;
+        };
+        fresnel_conductor(wo.dot(half), self.eta, self.k) * specular(self.alpha, wo, wi, half)
+    }
+
+    fn sample(&self, wo: Vec3) -> Option<Vec3> {
+        sample_specular(self.alpha, wo)
+    }
+
+    fn pdf(&self, wo: Vec3, wi: Vec3) -> f32 {
+        specular_pdf(self.alpha, wo, wi)
+    }
+}
+
+/// Diffuse base under a rough dielectric coating, such as plastic or varnished wood.
+pub struct Plastic {
+    pub alpha: f32,
+    /// Index of refraction of the coating.
+    pub eta: f32,
+    pub albedo: Vec3,
+}
+
+impl Plastic {
+    /// Chance of sampling the coating rather than the base, from their rough contributions.
+    fn specular_probability(&self, wo: Vec3) -> f32 {
+        let specular = reflectance(wo.z, self.eta);
+        let diffuse = (1.0 - specular) * self.albedo.element_sum() / 3.0;
+        (specular / (specular + diffuse)).clamp(0.1, 0.9)
+    }
+}
+
+impl Bsdf for Plastic {
+    fn eval(&self, wo: Vec3, wi: Vec3) -> Vec3 {
+        let Some(half) = half_vector(wo, wi) else {
+            return Vec3::ZERO;
+        };
+        let coating = reflectance(wo.dot(half), self.eta) * specular(self.alpha, wo, wi, half);
+        // Light reaching the base goes through the coating twice
+        let transmission =
+            (1.0 - reflectance(wo.z, self.eta)) * (1.0 - reflectance(wi.z, self.eta));
+        Vec3::splat(coating) + self.albedo * (transmission * wi.z / PI)
+    }
+
+    fn sample(&self, wo: Vec3) -> Option<Vec3> {
+        if wo.z <= 0.0 {
+            return None;
+        }
+        if rand::random::<f32>() < self.specular_probability(wo) {
+            sample_specular(self.alpha, wo)
+        } else {
+            Some(sample_cosine_hemisphere())
+        }
+    }
+
+    fn pdf(&self, wo: Vec3, wi: Vec3) -> f32 {
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return 0.0;
+        }
+        let probability = self.specular_probability(wo);
+        probability * specular_pdf(self.alpha, wo, wi) + (1.0 - probability) * wi.z / PI
+    }
+}
+
+/// Rough dielectric interface reflecting light, the coat of a layered material. The light it
+/// lets through is left to the material under it.
+pub struct Coating {
+    pub alpha: f32,
+    pub eta: f32,
+}
+
+impl Bsdf for Coating {
+    fn eval(&self, wo: Vec3, wi: Vec3) -> Vec3 {
+        let Some(half) = half_vector(wo, wi) else {
+            return Vec3::ZERO;
+        };
+        Vec3::splat(reflectance(wo.dot(half), self.eta) * specular(self.alpha, wo, wi, half))
+    }
+
+    fn sample(&self, wo: Vec3) -> Option<Vec3> {
+        sample_specular(self.alpha, wo)
+    }
+
+    fn pdf(&self, wo: Vec3, wi: Vec3) -> f32 {
+        specular_pdf(self.alpha, wo, wi)
+    }
+}
+
+/// Metallic-roughness material of the glTF specification: a blend between a rough metal and
+/// a diffuse base under a rough coating with an index of refraction of 1.5.
+pub struct Principled {
+    pub alpha: f32,
+    pub base_color: Vec3,
+    pub metallic: f32,
+}
+
+/// Normal incidence reflectance of the coating of non-metals.
+const DIELECTRIC_F0: f32 = 0.04;
+
+impl Principled {
+    fn specular_color(&self) -> Vec3 {
+        Vec3::splat(DIELECTRIC_F0).lerp(self.base_color, self.metallic)
+    }
+
+    fn diffuse_color(&self) -> Vec3 {
+        self.base_color * (1.0 - self.metallic)
+    }
+
+    /// Chance of sampling the specular lobe rather than the diffuse one.
+    fn specular_probability(&self, wo: Vec3) -> f32 {
+        let specular = fresnel_schlick(wo.z, self.specular_color()).element_sum() / 3.0;
+        let diffuse = (1.0 - specular) * self.diffuse_color().element_sum() / 3.0;
+        if diffuse <= 0.0 {
+            return 1.0;
+        }
+        (specular / (specular + diffuse)).clamp(0.1, 0.9)
+    }
+}
+
+impl Bsdf for Principled {
+    fn eval(&self, wo: Vec3, wi: Vec3) -> Vec3 {
+        let Some(half) = half_vector(wo, wi) else {
+            return Vec3::ZERO;
+        };
+        let fresnel = fresnel_schlick(wo.dot(half), self.specular_color());
+        let specular = fresnel * specular(self.alpha, wo, wi, half);
+        // Only the light the coating doesn't reflect towards the viewer reaches the diffuse
+        // base. Taking it at the half vector instead would add energy at grazing angles, where
+        // diffuse directions have half vectors far from the view.
+        let dielectric_fresnel = fresnel_schlick(wo.z, Vec3::splat(DIELECTRIC_F0)).x;
+        specular + self.diffuse_color() * ((1.0 - dielectric_fresnel) * wi.z / PI)
+    }
+
+    fn sample(&self, wo: Vec3) -> Option<Vec3> {
+        if wo.z <= 0.0 {
+            return None;
+        }
+        if rand::random::<f32>() < self.specular_probability(wo) {
+            sample_specular(self.alpha, wo)
+        } else {
+            Some(sample_cosine_hemisphere())
+        }
+    }
+
+    fn pdf(&self, wo: Vec3, wi: Vec3) -> f32 {
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return 0.0;
+        }
+        let probability = self.specular_probability(wo);
+        probability * specular_pdf(self.alpha, wo, wi) + (1.0 - probability) * wi.z / PI
+    }
+}
+
+fn half_vector(wo: Vec3, wi: Vec3) -> Option<Vec3> {
+    if wo.z <= 0.0 || wi.z <= 0.0 {
+        return None;
+    }
+    (wo + wi).try_normalize()
+}
+
+/// GGX specular reflection without the Fresnel term, times the cosine of `wi`.
+fn specular(alpha: f32, wo: Vec3, wi: Vec3, half: Vec3) -> f32 {
+    distribution(alpha, half) * masking_shadowing(alpha, wo, wi) / (4.0 * wo.z)
+}
+
+fn distribution(alpha: f32, half: Vec3) -> f32 {
+    let alpha2 = alpha * alpha;
+    let denominator = half.z * half.z * (alpha2 - 1.0) + 1.0;
+    alpha2 / (PI * denominator * denominator)
+}
+
+fn lambda(alpha: f32, direction: Vec3) -> f32 {
+    let cos2 = direction.z * direction.z;
+    let tan2 = (1.0 - cos2).max(0.0) / cos2;
+    0.5 * ((1.0 + alpha * alpha * tan2).sqrt() - 1.0)
+}
+
+fn masking(alpha: f32, direction: Vec3) -> f32 {
+    1.0 / (1.0 + lambda(alpha, direction))
+}
+
+fn masking_shadowing(alpha: f32, wo: Vec3, wi: Vec3) -> f32 {
+    1.0 / (1.0 + lambda(alpha, wo) + lambda(alpha, wi))
+}
+
+/// Reflects `wo` on a normal sampled from the visible normals, following Heitz 2018.
+fn sample_specular(alpha: f32, wo: Vec3) -> Option<Vec3> {
+    if wo.z <= 0.0 {
+        return None;
+    }
+
+    // Stretch the view so the distribution becomes a hemisphere
+    let view = Vec3::new(alpha * wo.x, alpha * wo.y, wo.z).normalize();
+    let length2 = view.x * view.x + view.y * view.y;
+    let t1 = if length2 > 0.0 {
+        Vec3::new(-view.y, view.x, 0.0) / length2.sqrt()
+    } else {
+        Vec3::X
+    };
+    let t2 = view.cross(t1);
+
+    let radius = rand::random::<f32>().sqrt();
+    let phi = 2.0 * PI * rand::random::<f32>();
+    let p1 = radius * phi.cos();
+    let s = 0.5 * (1.0 + view.z);
+    let p2 = (1.0 - s) * (1.0 - p1 * p1).sqrt() + s * radius * phi.sin();
+    let normal = p1 * t1 + p2 * t2 + (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt() * view;
+    let half = Vec3::new(alpha * normal.x, alpha * normal.y, normal.z.max(1e-6)).normalize();
+
+    let wi = 2.0 * wo.dot(half) * half - wo;
+    (wi.z > 0.0).then_some(wi)
+}
+
+fn specular_pdf(alpha: f32, wo: Vec3, wi: Vec3) -> f32 {
+    let Some(half) = half_vector(wo, wi) else {
+        return 0.0;
+    };
+    masking(alpha, wo) * distribution(alpha, half) / (4.0 * wo.z)
+}
+
+fn sample_cosine_hemisphere() -> Vec3 {
+    let radius = rand::random::<f32>().sqrt();
+    let phi = 2.0 * PI * rand::random::<f32>();
+    Vec3::new(
+        radius * phi.cos(),
+        radius * phi.sin(),
+        (1.0 - radius * radius).max(0.0).sqrt(),
+    )
+}
+
+/// Schlick approximation of the reflectance from the reflectance at normal incidence.
+fn fresnel_schlick(cos_theta: f32, f0: Vec3) -> Vec3 {
+    f0 + (Vec3::ONE - f0) * (1.0 - cos_theta.clamp(0.0, 1.0)).powi(5)
+}
+
+/// Exact unpolarized reflectance of a conductor.
+fn fresnel_conductor(cos_theta: f32, eta: Vec3, k: Vec3) -> Vec3 {
+    let cos2 = cos_theta.clamp(0.0, 1.0).powi(2);
+    let sin2 = 1.0 - cos2;
+    let eta2 = eta * eta;
+    let k2 = k * k;
+
+    let t0 = eta2 - k2 - Vec3::splat(sin2);
+    let a2_plus_b2 = (t0 * t0 + 4.0 * eta2 * k2).map(f32::sqrt);
+    let t1 = a2_plus_b2 + Vec3::splat(cos2);
+    let a = (0.5 * (a2_plus_b2 + t0)).max(Vec3::ZERO).map(f32::sqrt);
+    let t2 = 2.0 * cos_theta * a;
+    let rs = (t1 - t2) / (t1 + t2);
+
+    let t3 = cos2 * a2_plus_b2 + Vec3::splat(sin2 * sin2);
+    let t4 = t2 * sin2;
+    let rp = rs * (t3 - t4) / (t3 + t4);
+
+    0.5 * (rp + rs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Bsdf, Coating, Conductor, Plastic, Principled, fresnel_conductor};
+    use glam::Vec3;
+    use std::f32::consts::PI;
+
+    const SAMPLES: usize = 100_000;
+
+    /// One of each BSDF, all reflecting as much as they can.
+    fn white_bsdfs(alpha: f32) -> Vec<(&'static str, Box<dyn Bsdf>)> {
+        vec![
+            (
+                "conductor",
+                Box::new(Conductor {
+                    alpha,
+                    eta: Vec3::splat(0.05),
+                    k: Vec3::splat(20.0),
+                }),
+            ),
+            (
+                "plastic",
+                Box::new(Plastic {
+                    alpha,
+                    eta: 1.5,
+                    albedo: Vec3::ONE,
+                }),
+            ),
+            ("coating", Box::new(Coating { alpha, eta: 1.5 })),
+            (
+                "metallic principled",
+                Box::new(Principled {
+                    alpha,
+                    base_color: Vec3::ONE,
+                    metallic: 1.0,
+                }),
+            ),
+            (
+                "dielectric principled",
+                Box::new(Principled {
+                    alpha,
+                    base_color: Vec3::ONE,
+                    metallic: 0.0,
+                }),
+            ),
+        ]
+    }
+
+    fn view(theta_degrees: f32) -> Vec3 {
+        let theta = theta_degrees.to_radians();
+        Vec3::new(theta.sin(), 0.0, theta.cos())
+    }
+
+    /// Midpoint rule over the upper hemisphere, in cosθ and φ so every cell has the same
+    /// solid angle.
+    fn integrate_hemisphere(f: impl Fn(Vec3) -> f32) -> f32 {
+        const STEPS: usize = 400;
+        let mut sum = 0.0_f64;
+        for i in 0..STEPS {
+            let cos_theta = (i as f32 + 0.5) / STEPS as f32;
+            let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+            for j in 0..STEPS {
+                let phi = 2.0 * PI * (j as f32 + 0.5) / STEPS as f32;
+                let direction = Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta);
+                sum += f64::from(f(direction));
+            }
+        }
+        (sum * f64::from(2.0 * PI) / (STEPS * STEPS) as f64) as f32
+    }
+
+    /// The density integrates to the fraction of samples kept, as the specular lobes lose the
+    /// directions they reflect below the surface.
+    #[test]
+    fn pdf_matches_sampling() {
+        for alpha in [0.2, 0.5, 1.0] {
+            for (name, bsdf) in white_bsdfs(alpha) {
+                for theta in [0.0, 45.0, 80.0] {
+                    let wo = view(theta);
+                    let integral = integrate_hemisphere(|wi| bsdf.pdf(wo, wi));
+                    let kept = (0..SAMPLES).filter(|_| bsdf.sample(wo).is_some()).count();
+                    let kept = kept as f32 / SAMPLES as f32;
+                    assert!(
+                        (integral - kept).abs() < 0.01,
+                        "{name} with alpha {alpha} at {theta}°: the pdf integrates to \
+                         {integral} but {kept} of the samples are kept"
+                    );
+                    assert!(
+                        integral <= 1.001,
+                        "{name}: the pdf integrates to {integral}"
+                    );
+                }
+            }
+        }
+    }
+
+    /// Surfaces that reflect everything they can don't reflect more light than they receive.
+    #[test]
+    fn white_furnace() {
+        for alpha in [0.2, 0.5, 1.0] {
+            for (name, bsdf) in white_bsdfs(alpha) {
+                for theta in [0.0, 45.0, 80.0] {
+                    let wo = view(theta);
+                    let albedo = (0..SAMPLES)
+                        .filter_map(|_| bsdf.sample(wo))
+                        .map(|wi| bsdf.eval(wo, wi) / bsdf.pdf(wo, wi))
+                        .sum::<Vec3>()
+                        / SAMPLES as f32;
+                    assert!(
+                        albedo.max_element() <= 1.01,
+                        "{name} with alpha {alpha} at {theta}° reflects {albedo}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn conductor_fresnel_at_normal_incidence() {
+        let eta = Vec3::new(0.27, 0.68, 1.32);
+        let k = Vec3::new(3.61, 2.62, 2.29);
+        let expected = ((eta - 1.0).powf(2.0) + k * k) / ((eta + 1.0).powf(2.0) + k * k);
+        let fresnel = fresnel_conductor(1.0, eta, k);
+        assert!(
+            (fresnel - expected).abs().max_element() < 1e-5,
+            "{fresnel} instead of {expected}"
+        );
+    }
+}
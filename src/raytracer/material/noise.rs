@@ -0,0 +1,94 @@
+//! Gradient noise for procedural textures, hashed from the lattice coordinates so it needs no
+//! tables and gives the same pattern on every run.
+
+use glam::{IVec3, Vec3};
+
+/// Improved Perlin noise, roughly in [-1, 1] and zero on every lattice point.
+pub fn perlin(point: Vec3) -> f32 {
+    let cell = point.floor();
+    let corner = cell.as_ivec3();
+    let local = point - cell;
+    let fade = local.map(|t| t * t * t * (t * (t * 6.0 - 15.0) + 10.0));
+
+    let gradient_dot =
+        |offset: IVec3| gradient(hash(corner + offset)).dot(local - offset.as_vec3());
+    let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+
+    let x00 = lerp(
+        gradient_dot(IVec3::new(0, 0, 0)),
+        gradient_dot(IVec3::new(1, 0, 0)),
+        fade.x,
+    );
+    let x10 = lerp(
+        gradient_dot(IVec3::new(0, 1, 0)),
+        gradient_dot(IVec3::new(1, 1, 0)),
+        fade.x,
+    );
+    let x01 = lerp(
+        gradient_dot(IVec3::new(0, 0, 1)),
+        gradient_dot(IVec3::new(1, 0, 1)),
+        fade.x,
+    );
+    let x11 = lerp(
+        gradient_dot(IVec3::new(0, 1, 1)),
+        gradient_dot(IVec3::new(1, 1, 1)),
+        fade.x,
+    );
+    lerp(lerp(x00, x10, fade.y), lerp(x01, x11, fade.y), fade.z)
+}
+
+/// Fractional Brownian motion: octaves of noise, each twice the frequency and half the
+/// amplitude of the previous one, normalized back to about [-1, 1].
+pub fn fbm(point: Vec3, octaves: u32) -> f32 {
+    octaves_sum(point, octaves, perlin)
+}
+
+/// Like [`fbm`] with the absolute value of each octave, in [0, 1], giving sharp creases.
+pub fn turbulence(point: Vec3, octaves: u32) -> f32 {
+    octaves_sum(point, octaves, |point| perlin(point).abs())
+}
+
+fn octaves_sum(point: Vec3, octaves: u32, noise: impl Fn(Vec3) -> f32) -> f32 {
+    let mut sum = 0.0;
+    let mut total_amplitude = 0.0;
+    let mut amplitude = 1.0;
+    let mut frequency = 1.0;
+    for _ in 0..octaves.max(1) {
+        sum += amplitude * noise(point * frequency);
+        total_amplitude += amplitude;
+        amplitude *= 0.5;
+        frequency *= 2.0;
+    }
+    sum / total_amplitude
+}
+
+fn hash(corner: IVec3) -> u32 {
+    let mut hash = (corner.x as u32)
+        .wrapping_mul(0x8da6_b343)
+        .wrapping_add((corner.y as u32).wrapping_mul(0xd816_3841))
+        .wrapping_add((corner.z as u32).wrapping_mul(0xcb1a_b31f));
+    hash ^= hash >> 16;
+    hash = hash.wrapping_mul(0x7feb_352d);
+    hash ^= hash >> 15;
+    hash = hash.wrapping_mul(0x846c_a68b);
+    hash ^ (hash >> 16)
+}
+
+/// One of the 12 edge directions of a cube, as in improved Perlin noise.
+fn gradient(hash: u32) -> Vec3 {
+    const GRADIENTS: [Vec3; 12] = [
+        Vec3::new(1.0, 1.0, 0.0),
+        Vec3::new(-1.0, 1.0, 0.0),
+        Vec3::new(1.0, -1.0, 0.0),
+        Vec3::new(-1.0, -1.0, 0.0),
+        Vec3::new(1.0, 0.0, 1.0),
+        Vec3::new(-1.0, 0.0, 1.0),
+        Vec3::new(1.0, 0.0, -1.0),
+        Vec3::new(-1.0, 0.0, -1.0),
+        Vec3::new(0.0, 1.0, 1.0),
+        Vec3::new(0.0, -1.0, 1.0),
+        Vec3::new(0.0, 1.0, -1.0),
+        Vec3::new(0.0, -1.0, -1.0),
+    ];
+    GRADIENTS[(hash % 12) as usize]
+}
@@ -0,0 +1,43 @@
+//! Wavelength sampling for dispersion, which the RGB renderer otherwise has no notion of.
+
+use glam::Vec3;
+use std::f32::consts::PI;
+
+/// Center and width in nanometers of the Gaussian response of each RGB channel.
+const RESPONSES: [(f32, f32); 3] = [(600.0, 35.0), (545.0, 30.0), (455.0, 25.0)];
+
+/// Wavelengths in nanometers of the Fraunhofer C, d and F lines, which define Abbe numbers.
+const LINE_C: f32 = 656.3;
+const LINE_D: f32 = 587.6;
+const LINE_F: f32 = 486.1;
+
+/// Samples a wavelength in nanometers, returning it with its RGB weight. The weights average
+/// to white, so white light stays white once every wavelength is accounted for.
+pub fn sample_wavelength() -> (f32, Vec3) {
+    // Pick a channel, then a wavelength from its response
+    let channel = ((rand::random::<f32>() * 3.0) as usize).min(2);
+    let (center, width) = RESPONSES[channel];
+    let radius = (-2.0 * (1.0 - rand::random::<f32>()).ln()).sqrt();
+    let wavelength = center + width * radius * (2.0 * PI * rand::random::<f32>()).cos();
+
+    let response = Vec3::from(RESPONSES.map(|(center, width)| {
+        let x = (wavelength - center) / width;
+        (-0.5 * x * x).exp() / (width * (2.0 * PI).sqrt())
+    }));
+    let pdf = response.element_sum() / 3.0;
+    let weight = if pdf > 0.0 {
+        response / pdf
+    } else {
+        Vec3::ZERO
+    };
+    (wavelength, weight)
+}
+
+/// Index of refraction at `wavelength` following Cauchy's equation, fitted to the index at
+/// the d line and the Abbe number.
+pub fn cauchy_index(refractive_index: f32, abbe_number: f32, wavelength: f32) -> f32 {
+    let b = (refractive_index - 1.0)
+        / (abbe_number * (1.0 / (LINE_F * LINE_F) - 1.0 / (LINE_C * LINE_C)));
+    let a = refractive_index - b / (LINE_D * LINE_D);
+    a + b / (wavelength * wavelength)
+}
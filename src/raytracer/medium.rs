@@ -0,0 +1,354 @@
+use glam::Vec3;
+use serde::{Deserialize, Serialize};
+use std::f32::consts::PI;
+
+/// Homogeneous participating medium, such as fog, smoke or the inside of a translucent object.
+///
+/// Paths find where they scatter by delta tracking and shadow rays estimate how much light gets
+/// through by ratio tracking, both against the largest extinction of the RGB channels so that
+/// the channels share their collisions.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Medium {
+    /// Fraction of light absorbed per unit of distance, for each RGB channel.
+    #[serde(default)]
+    pub absorption: Vec3,
+    /// Fraction of light scattered in another direction per unit of distance.
+    #[serde(default)]
+    pub scattering: Vec3,
+    /// Henyey-Greenstein asymmetry, from -1 for backward to 1 for forward scattering.
+    #[serde(default)]
+    pub asymmetry: f32,
+}
+
+/// Where a ray travelling through a medium interacts with it first.
+pub struct FreeFlight {
+    /// Distance to the scattering event, or `None` if the ray reaches the end of its segment.
+    pub distance: Option<f32>,
+    /// Throughput weight of the event, whose expectation is the transmittance (times the
+    /// scattering) over the density of the event.
+    pub weight: Vec3,
+}
+
+/// Most media a path can be nested inside of, past which the inner ones are ignored.
+const MAX_NESTED_MEDIA: usize = 4;
+
+/// Media of the geometries a path is inside of, the innermost last, so that leaving one goes
+/// back to the medium around it, such as ice in water or smoke in a glass box.
+#[derive(Clone, Copy)]
+pub struct MediumStack<'a> {
+    /// Medium outside of every geometry.
+    fog: Option<&'a Medium>,
+    /// Geometry index and medium of each geometry entered and not left yet.
+    entered: [Option<(usize, &'a Medium)>; MAX_NESTED_MEDIA],
+    len: usize,
+}
+
+impl Medium {
+    fn extinction(&self) -> Vec3 {
+        self.absorption + self.scattering
+    }
+
+    /// Fraction of light going through `distance` of the medium.
+    pub fn transmittance(&self, distance: f32) -> Vec3 {
+        // Infinite distances only let through the channels the medium doesn't attenuate
+        self.extinction().map(|extinction| {
+            if extinction > 0.0 {
+                (-extinction * distance).exp()
+            } else {
+                1.0
+            }
+        })
+    }
+
+    /// Ratio tracking estimate of [`Medium::transmittance`]: the fraction of light left after
+    /// the null collisions along `distance`.
+    pub fn estimate_transmittance(&self, distance: f32) -> Vec3 {
+        let extinction = self.extinction();
+        let majorant = extinction.max_element();
+        if majorant <= 0.0 || distance.is_infinite() {
+            // No collisions to track, or infinitely many
+            return self.transmittance(distance);
+        }
+
+        let null = Vec3::ONE - extinction / majorant;
+        let mut transmittance = Vec3::ONE;
+        let mut travelled = 0.0;
+        loop {
+            travelled -= (1.0 - rand::random::<f32>()).ln() / majorant;
+            if travelled >= distance {
+                return transmittance;
+            }
+            transmittance *= null;
+            if transmittance.max_element() <= 0.0 {
+                return Vec3::ZERO;
+            }
+        }
+    }
+
+    /// Samples a scattering distance along a segment of `max_distance` by delta tracking.
+    /// Each tentative collision is a real one or a null one with probabilities following the
+    /// channels that still carry light, and the weight makes up for the other channels.
+    pub fn sample_flight(&self, max_distance: f32) -> FreeFlight {
+        let extinction = self.extinction();
+        let majorant = extinction.max_element();
+        let null = majorant - extinction;
+
+        let mut weight = Vec3::ONE;
+        let mut distance = 0.0;
+        loop {
+            let scatter = (weight * self.scattering).element_sum();
+            if scatter <= 0.0 {
+                // Nothing left to scatter, only the light going through the rest matters
+                return FreeFlight {
+                    distance: None,
+                    weight: weight * self.estimate_transmittance(max_distance - distance),
+                };
+            }
+
+            distance -= (1.0 - rand::random::<f32>()).ln() / majorant;
+            if distance >= max_distance {
+                return FreeFlight {
+                    distance: None,
+                    weight,
+                };
+            }
+
+            let scatter_probability = scatter / (scatter + (weight * null).element_sum());
+            if rand::random::<f32>() < scatter_probability {
+                return FreeFlight {
+                    distance: Some(distance),
+                    weight: weight * self.scattering / (majorant * scatter_probability),
+                };
+            }
+            weight *= null / (majorant * (1.0 - scatter_probability));
+        }
+    }
+
+    fn asymmetry(&self) -> f32 {
+        self.asymmetry.clamp(-0.99, 0.99)
+    }
+
+    /// Henyey-Greenstein density of scattering from the `incoming` travel direction to
+    /// `outgoing`, both normalized. It is also the solid angle density of [`Medium::sample_phase`].
+    pub fn phase(&self, incoming: Vec3, outgoing: Vec3) -> f32 {
+        let g = self.asymmetry();
+        let denominator = 1.0 + g * g - 2.0 * g * incoming.dot(outgoing);
+        (1.0 - g * g) / (4.0 * PI * denominator * denominator.max(0.0).sqrt())
+    }
+
+    /// Samples a scattered direction for a ray travelling along `incoming` (normalized).
+    pub fn sample_phase(&self, incoming: Vec3) -> Vec3 {
+        let g = self.asymmetry();
+        let random: f32 = rand::random();
+        let cos_theta = if g.abs() < 1e-3 {
+            1.0 - 2.0 * random
+        } else {
+            let square = (1.0 - g * g) / (1.0 - g + 2.0 * g * random);
+            (1.0 + g * g - square * square) / (2.0 * g)
+        }
+        .clamp(-1.0, 1.0);
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * PI * rand::random::<f32>();
+
+        let (u, v) = incoming.any_orthonormal_pair();
+        (u * phi.cos() * sin_theta + v * phi.sin() * sin_theta + incoming * cos_theta).normalize()
+    }
+}
+
+impl<'a> MediumStack<'a> {
+    /// Stack of a path outside of every geometry.
+    pub fn new(fog: Option<&'a Medium>) -> Self {
+        MediumStack {
+            fog,
+            entered: [None; MAX_NESTED_MEDIA],
+            len: 0,
+        }
+    }
+
+    /// Medium the path travels through.
+    pub fn current(&self) -> Option<&'a Medium> {
+        match self.len {
+            0 => self.fog,
+            len => self.entered[len - 1].map(|(_, medium)| medium),
+        }
+    }
+
+    /// The stack once the path goes into `geometry`, filled with `medium`. Going in again
+    /// without leaving, such as after reflecting inside of it, changes nothing.
+    pub fn enter(mut self, geometry: usize, medium: &'a Medium) -> Self {
+        let entered = &self.entered[..self.len];
+        if self.len < MAX_NESTED_MEDIA
+            && !entered
+                .iter()
+                .flatten()
+                .any(|&(index, _)| index == geometry)
+        {
+            self.entered[self.len] = Some((geometry, medium));
+            self.len += 1;
+        }
+        self
+    }
+
+    /// The stack once the path goes out of `geometry`, which overlapping media may leave
+    /// before the ones entered after it.
+    pub fn leave(mut self, geometry: usize) -> Self {
+        let entered = &self.entered[..self.len];
+        if let Some(position) = entered
+            .iter()
+            .position(|entry| entry.is_some_and(|(index, _)| index == geometry))
+        {
+            self.entered.copy_within(position + 1..self.len, position);
+            self.len -= 1;
+            self.entered[self.len] = None;
+        }
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Medium, MediumStack};
+    use glam::Vec3;
+    use std::f32::consts::PI;
+
+    const SAMPLES: u32 = 200_000;
+
+    /// Absorbs and scatters each channel differently, so the channels need their own weights.
+    const COLORED: Medium = Medium {
+        absorption: Vec3::new(0.5, 0.1, 1.0),
+        scattering: Vec3::new(1.0, 0.3, 0.2),
+        asymmetry: 0.0,
+    };
+
+    fn assert_close(actual: Vec3, expected: Vec3, what: &str) {
+        assert!(
+            actual.abs_diff_eq(expected, 0.01),
+            "{what}: {actual} instead of {expected}"
+        );
+    }
+
+    #[test]
+    fn flights_reproduce_transmittance() {
+        // Light going through, then scattered light in the first and second halves, which is
+        // the scattering times the transmittance integrated over each half
+        let mut weights = [Vec3::ZERO; 3];
+        for _ in 0..SAMPLES {
+            let flight = COLORED.sample_flight(1.0);
+            let index = match flight.distance {
+                None => 0,
+                Some(distance) if distance < 0.5 => 1,
+                Some(_) => 2,
+            };
+            weights[index] += flight.weight;
+        }
+
+        let albedo = COLORED.scattering / COLORED.extinction();
+        let expected = [
+            COLORED.transmittance(1.0),
+            albedo * (1.0 - COLORED.transmittance(0.5)),
+            albedo * (COLORED.transmittance(0.5) - COLORED.transmittance(1.0)),
+        ];
+        for (name, (weight, expected)) in ["through", "first half", "second half"]
+            .iter()
+            .zip(weights.iter().zip(expected))
+        {
+            assert_close(*weight / SAMPLES as f32, expected, name);
+        }
+    }
+
+    #[test]
+    fn flights_end_in_endless_media() {
+        let smoke = Medium {
+            absorption: Vec3::splat(0.5),
+            scattering: Vec3::splat(1.5),
+            asymmetry: 0.0,
+        };
+        let flight = smoke.sample_flight(f32::INFINITY);
+        assert!(flight.distance.is_some());
+        assert_close(flight.weight, Vec3::splat(0.75), "grey scattering");
+
+        let tinted = Medium {
+            absorption: Vec3::new(0.0, 1.0, 2.0),
+            scattering: Vec3::ZERO,
+            asymmetry: 0.0,
+        };
+        let flight = tinted.sample_flight(f32::INFINITY);
+        assert!(flight.distance.is_none());
+        assert_eq!(flight.weight, Vec3::new(1.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn ratio_tracking_reproduces_transmittance() {
+        let estimate: Vec3 = (0..SAMPLES)
+            .map(|_| COLORED.estimate_transmittance(1.0))
+            .sum();
+        assert_close(
+            estimate / SAMPLES as f32,
+            COLORED.transmittance(1.0),
+            "transmittance",
+        );
+    }
+
+    #[test]
+    fn phase_sampling_matches_phase() {
+        const BINS: usize = 20;
+        let incoming = Vec3::new(1.0, 2.0, 3.0).normalize();
+        for asymmetry in [-0.3, 0.0, 0.6] {
+            let medium = Medium {
+                asymmetry,
+                ..COLORED
+            };
+
+            // Fraction of the directions in bins of the cosine to the incoming direction
+            let mut histogram = [0u32; BINS];
+            for _ in 0..SAMPLES {
+                let cos_theta = medium.sample_phase(incoming).dot(incoming);
+                let bin = ((cos_theta + 1.0) / 2.0 * BINS as f32) as usize;
+                histogram[bin.min(BINS - 1)] += 1;
+            }
+
+            // The phase function integrated over the band of directions of each bin
+            for (bin, &count) in histogram.iter().enumerate() {
+                let steps = 100;
+                let width = 2.0 / (BINS * steps) as f32;
+                let expected: f32 = (0..steps)
+                    .map(|step| {
+                        let cos_theta = -1.0 + ((bin * steps + step) as f32 + 0.5) * width;
+                        let outgoing =
+                            Vec3::new(0.0, (1.0 - cos_theta * cos_theta).sqrt(), cos_theta);
+                        2.0 * PI * medium.phase(Vec3::Z, outgoing) * width
+                    })
+                    .sum();
+                let fraction = count as f32 / SAMPLES as f32;
+                assert!(
+                    (fraction - expected).abs() < 0.005,
+                    "asymmetry {asymmetry}, bin {bin}: {fraction} instead of {expected}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn nested_media() {
+        let [fog, water, ice] = [0.1, 0.5, 2.0].map(|absorption| Medium {
+            absorption: Vec3::splat(absorption),
+            ..COLORED
+        });
+        let current = |media: MediumStack| media.current().map(|medium| medium.absorption.x);
+
+        let outside = MediumStack::new(Some(&fog));
+        let in_water = outside.enter(1, &water);
+        let in_ice = in_water.enter(2, &ice);
+        assert_eq!(current(in_ice), Some(2.0));
+        // Reflecting inside of the ice keeps the water around it
+        assert_eq!(current(in_ice.enter(2, &ice).leave(2)), Some(0.5));
+        assert_eq!(current(in_ice.leave(2)), Some(0.5));
+        assert_eq!(current(in_ice.leave(2).leave(1)), Some(0.1));
+        // Overlapping media can be left in any order
+        assert_eq!(current(in_ice.leave(1)), Some(2.0));
+        assert_eq!(current(in_ice.leave(1).leave(2)), Some(0.1));
+        // Leaving a geometry that was never entered changes nothing
+        assert_eq!(current(in_water.leave(3)), Some(0.5));
+        assert_eq!(current(MediumStack::new(None)), None);
+    }
+}
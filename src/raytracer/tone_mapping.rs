@@ -0,0 +1,221 @@
+use glam::{Mat3, Vec3};
+use serde::{Deserialize, Serialize};
+
+/// Operator that compresses the linear radiance into the displayable [0, 1] range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum ToneMapOperator {
+    /// Clip every channel at 1
+    Clamp,
+    /// Per channel x / (1 + x)
+    Reinhard,
+    /// Stephen Hill's fit of the ACES reference rendering and output transforms
+    AcesFilmic,
+    /// Troy Sobotka's AgX with the default look
+    Agx,
+}
+
+impl ToneMapOperator {
+    pub const ALL: [ToneMapOperator; 4] = [
+        ToneMapOperator::Clamp,
+        ToneMapOperator::Reinhard,
+        ToneMapOperator::AcesFilmic,
+        ToneMapOperator::Agx,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ToneMapOperator::Clamp => "Clamp",
+            ToneMapOperator::Reinhard => "Reinhard",
+            ToneMapOperator::AcesFilmic => "ACES filmic",
+            ToneMapOperator::Agx => "AgX",
+        }
+    }
+
+    fn apply(&self, color: Vec3) -> Vec3 {
+        match self {
+            ToneMapOperator::Clamp => color,
+            ToneMapOperator::Reinhard => color / (Vec3::ONE + color),
+            ToneMapOperator::AcesFilmic => aces_filmic(color),
+            ToneMapOperator::Agx => agx(color),
+        }
+    }
+}
+
+/// Converts the linear radiance of the canvas into display values.
+/// Used both by the interactive viewer and when saving images, so they always match.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ToneMapping {
+    pub operator: ToneMapOperator,
+    /// Exposure adjustment in stops, applied before the operator.
+    pub exposure: f32,
+    /// Extra gamma applied on top of the sRGB transfer function. 1 leaves it unchanged.
+    pub gamma: f32,
+}
+
+impl Default for ToneMapping {
+    fn default() -> Self {
+        Self {
+            operator: ToneMapOperator::Clamp,
+            exposure: 0.0,
+            gamma: 1.0,
+        }
+    }
+}
+
+impl ToneMapping {
+    /// Maps a linear radiance value to sRGB encoded values in [0, 1].
+    pub fn apply(&self, radiance: Vec3) -> Vec3 {
+        let exposed = radiance.max(Vec3::ZERO) * self.exposure.exp2();
+        let mapped = self
+            .operator
+            .apply(exposed)
+            .clamp(Vec3::ZERO, Vec3::ONE)
+            .powf(1.0 / self.gamma);
+
+        Vec3::new(
+            linear_to_srgb(mapped.x),
+            linear_to_srgb(mapped.y),
+            linear_to_srgb(mapped.z),
+        )
+    }
+
+    pub fn apply_to_bytes(&self, radiance: Vec3) -> [u8; 3] {
+        let encoded = self.apply(radiance) * 255.0 + Vec3::splat(0.5);
+        [encoded.x as u8, encoded.y as u8, encoded.z as u8]
+    }
+}
+
+fn linear_to_srgb(value: f32) -> f32 {
+    if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+fn aces_filmic(color: Vec3) -> Vec3 {
+    // sRGB => XYZ => D65_2_D60 => AP1 => RRT_SAT
+    const INPUT: Mat3 = Mat3::from_cols_array(&[
+        0.59719, 0.07600, 0.02840, //
+        0.35458, 0.90834, 0.13383, //
+        0.04823, 0.01566, 0.83777,
+    ]);
+    // ODT_SAT => XYZ => D60_2_D65 => sRGB
+    const OUTPUT: Mat3 = Mat3::from_cols_array(&[
+        1.60475, -0.10208, -0.00327, //
+        -0.53108, 1.10813, -0.07276, //
+        -0.07367, -0.00605, 1.07602,
+    ]);
+
+    let v = INPUT * color;
+    let a = v * (v + Vec3::splat(0.0245786)) - Vec3::splat(0.000090537);
+    let b = v * (0.983729 * v + Vec3::splat(0.432951)) + Vec3::splat(0.238081);
+    OUTPUT * (a / b)
+}
+
+fn agx(color: Vec3) -> Vec3 {
+    const INSET: Mat3 = Mat3::from_cols_array(&[
+        0.84247906, 0.04232824, 0.04237565, //
+        0.0784336, 0.87846864, 0.0784336, //
+        0.07922375, 0.07916613, 0.879143,
+    ]);
+    const OUTSET: Mat3 = Mat3::from_cols_array(&[
+        1.196879,
+        -0.05289685,
+        -0.05297164, //
+        -0.09802088,
+        1.1519031,
+        -0.09804345, //
+        -0.09902974,
+        -0.09896118,
+        1.1510737,
+    ]);
+    const MIN_EV: f32 = -12.47393;
+    const MAX_EV: f32 = 4.026069;
+
+    let v = (INSET * color).max(Vec3::splat(1e-10));
+    let v = Vec3::new(v.x.log2(), v.y.log2(), v.z.log2())
+        .clamp(Vec3::splat(MIN_EV), Vec3::splat(MAX_EV));
+    let x = (v - Vec3::splat(MIN_EV)) / (MAX_EV - MIN_EV);
+
+    // Sixth order polynomial approximation of the default AgX contrast curve
+    let x2 = x * x;
+    let x4 = x2 * x2;
+    let curve =
+        15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x
+            - Vec3::splat(0.00232);
+
+    // The curve outputs display encoded values, bring them back to linear
+    (OUTSET * curve).max(Vec3::ZERO).powf(2.2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ToneMapOperator, ToneMapping};
+    use glam::Vec3;
+
+    fn tone_mapping(operator: ToneMapOperator) -> ToneMapping {
+        ToneMapping {
+            operator,
+            ..ToneMapping::default()
+        }
+    }
+
+    #[test]
+    fn black_stays_black() {
+        for operator in ToneMapOperator::ALL {
+            let mapped = tone_mapping(operator).apply(Vec3::ZERO);
+            assert_eq!(
+                mapped,
+                Vec3::ZERO,
+                "{} maps black to {mapped}",
+                operator.name()
+            );
+        }
+    }
+
+    /// Greys get brighter in every channel. Saturated colors only in luminance, as the output
+    /// matrix of AgX takes a little from each channel as the others grow, which even dims them
+    /// by far less than a byte step once their brightest channel is past the top of the curve.
+    #[test]
+    fn operators_are_monotonic() {
+        const LUMINANCE: Vec3 = Vec3::new(0.2126, 0.7152, 0.0722);
+
+        for operator in ToneMapOperator::ALL {
+            let tone_mapping = tone_mapping(operator);
+            let mut previous_grey = Vec3::ZERO;
+            let mut previous_luminance = 0.0;
+            // Tenths of a stop from 2⁻¹⁰ to 2⁸
+            for step in 0..=180 {
+                let radiance = (step as f32 / 10.0 - 10.0).exp2();
+
+                let grey = tone_mapping.apply(Vec3::splat(radiance));
+                assert!(
+                    grey.cmpge(previous_grey - Vec3::splat(1e-6)).all(),
+                    "{} goes down from {previous_grey} to {grey} at {radiance}",
+                    operator.name()
+                );
+                previous_grey = grey;
+
+                let luminance = tone_mapping
+                    .apply(Vec3::new(1.0, 0.5, 0.1) * radiance)
+                    .dot(LUMINANCE);
+                assert!(
+                    luminance >= previous_luminance - 1e-4,
+                    "{} goes down from {previous_luminance} to {luminance} at {radiance}",
+                    operator.name()
+                );
+                previous_luminance = luminance;
+            }
+        }
+    }
+
+    #[test]
+    fn clamp_encodes_srgb() {
+        let tone_mapping = tone_mapping(ToneMapOperator::Clamp);
+        assert_eq!(tone_mapping.apply_to_bytes(Vec3::splat(0.5)), [188; 3]);
+        assert_eq!(tone_mapping.apply_to_bytes(Vec3::ZERO), [0; 3]);
+        assert_eq!(tone_mapping.apply_to_bytes(Vec3::splat(4.0)), [255; 3]);
+    }
+}
@@ -0,0 +1,408 @@
+use crate::raytracer::tracer::naive::NaiveObject;
+use crate::raytracer::tracer::{TraceResult, Tracer, TracerStats};
+use crate::raytracer::world::{Aabb, Geometry, Ray};
+use glam::Vec3;
+use std::ops::Range;
+use std::time::Instant;
+
+/// Number of buckets used to evaluate the surface area heuristic along each axis.
+const SAH_BUCKETS: usize = 12;
+/// Leaves with this many primitives or fewer are never split.
+const MAX_LEAF_SIZE: usize = 2;
+/// Cost of visiting a node relative to intersecting a primitive.
+const TRAVERSAL_COST: f32 = 1.0;
+
+/// Bounding volume hierarchy built with the surface area heuristic over the same primitives
+/// as [`crate::raytracer::tracer::naive::NaiveTracer`].
+pub struct BvhTracer {
+    objects: Vec<NaiveObject>,
+    nodes: Vec<BvhNode>,
+    stats: TracerStats,
+}
+
+struct BvhNode {
+    bounds: Aabb,
+    /// Index of the first object for leaves, or of the left child for interior nodes.
+    /// The right child is always stored right after the left one.
+    first: usize,
+    /// Number of objects in the leaf, or zero for interior nodes.
+    count: usize,
+}
+
+struct BuildPrimitive {
+    object_index: usize,
+    bounds: Aabb,
+    centroid: Vec3,
+}
+
+impl Tracer for BvhTracer {
+    fn build(geometry: &[Geometry]) -> Self {
+        let instant = Instant::now();
+        let objects =
+            NaiveObject::from_geometry(geometry, |geometry| Box::new(BvhTracer::build(geometry)));
+
+        let mut primitives: Vec<_> = objects
+            .iter()
+            .enumerate()
+            .map(|(object_index, object)| {
+                let bounds = object.bounds();
+                BuildPrimitive {
+                    object_index,
+                    bounds,
+                    centroid: bounds.centroid(),
+                }
+            })
+            .collect();
+
+        let mut nodes = Vec::with_capacity(2 * primitives.len().max(1));
+        nodes.push(BvhNode {
+            bounds: Aabb::EMPTY,
+            first: 0,
+            count: 0,
+        });
+        Self::build_node(&mut nodes, 0, &mut primitives, 0);
+
+        // Reorder the objects so each leaf references a contiguous range
+        let mut objects: Vec<_> = objects.into_iter().map(Some).collect();
+        let objects = primitives
+            .iter()
+            .map(|primitive| objects[primitive.object_index].take().unwrap())
+            .collect::<Vec<_>>();
+
+        let stats = TracerStats {
+            primitive_count: objects.iter().map(NaiveObject::primitive_count).sum(),
+            build_time: instant.elapsed(),
+        };
+
+        Self {
+            objects,
+            nodes,
+            stats,
+        }
+    }
+
+    fn trace(&self, ray: &Ray, range: &Range<f32>) -> Option<TraceResult> {
+        let inv_direction = ray.direction.recip();
+        let mut range = range.clone();
+        let mut closest_hit: Option<TraceResult> = None;
+
+        let mut stack = Vec::with_capacity(64);
+        stack.push(0);
+
+        while let Some(node_index) = stack.pop() {
+            let node: &BvhNode = &self.nodes[node_index];
+            if node.bounds.intersect(ray, inv_direction, &range).is_none() {
+                continue;
+            }
+
+            if node.count > 0 {
+                for object in &self.objects[node.first..node.first + node.count] {
+                    if let Some(hit) = object.hit(object.geometry_index, ray, &range) {
+                        range.end = hit.distance;
+                        closest_hit = Some(hit);
+                    }
+                }
+                continue;
+            }
+
+            // Visit the nearest child first so the range shrinks as early as possible
+            let left = node.first;
+            let right = node.first + 1;
+            let left_distance = self.nodes[left]
+                .bounds
+                .intersect(ray, inv_direction, &range);
+            let right_distance = self.nodes[right]
+                .bounds
+                .intersect(ray, inv_direction, &range);
+
+            match (left_distance, right_distance) {
+                (Some(left_distance), Some(right_distance)) => {
+                    if left_distance <= right_distance {
+                        stack.push(right);
+                        stack.push(left);
+                    } else {
+                        stack.push(left);
+                        stack.push(right);
+                    }
+                }
+                (Some(_), None) => stack.push(left),
+                (None, Some(_)) => stack.push(right),
+                (None, None) => {}
+            }
+        }
+
+        closest_hit
+    }
+
+    fn occluded(&self, ray: &Ray, range: &Range<f32>) -> bool {
+        let inv_direction = ray.direction.recip();
+
+        let mut stack = Vec::with_capacity(64);
+        stack.push(0);
+
+        while let Some(node_index) = stack.pop() {
+            let node: &BvhNode = &self.nodes[node_index];
+            if node.bounds.intersect(ray, inv_direction, range).is_none() {
+                continue;
+            }
+
+            if node.count > 0 {
+                let objects = &self.objects[node.first..node.first + node.count];
+                if objects.iter().any(|object| object.occludes(ray, range)) {
+                    return true;
+                }
+            } else {
+                stack.push(node.first);
+                stack.push(node.first + 1);
+            }
+        }
+
+        false
+    }
+
+    fn bounds(&self) -> Aabb {
+        self.nodes[0].bounds
+    }
+
+    fn stats(&self) -> TracerStats {
+        self.stats
+    }
+}
+
+impl BvhTracer {
+    fn build_node(
+        nodes: &mut Vec<BvhNode>,
+        node_index: usize,
+        primitives: &mut [BuildPrimitive],
+        first: usize,
+    ) {
+        let bounds = primitives.iter().fold(Aabb::EMPTY, |bounds, primitive| {
+            bounds.union(primitive.bounds)
+        });
+        nodes[node_index] = BvhNode {
+            bounds,
+            first,
+            count: primitives.len(),
+        };
+
+        if primitives.len() <= MAX_LEAF_SIZE {
+            return;
+        }
+
+        let Some(split) = Self::find_sah_split(primitives, &bounds) else {
+            return;
+        };
+
+        let left = nodes.len();
+        nodes.push(BvhNode {
+            bounds: Aabb::EMPTY,
+            first: 0,
+            count: 0,
+        });
+        nodes.push(BvhNode {
+            bounds: Aabb::EMPTY,
+            first: 0,
+            count: 0,
+        });
+        nodes[node_index].first = left;
+        nodes[node_index].count = 0;
+
+        let (left_primitives, right_primitives) = primitives.split_at_mut(split);
+        Self::build_node(nodes, left, left_primitives, first);
+        Self::build_node(nodes, left + 1, right_primitives, first + split);
+    }
+
+    /// Partitions the primitives along the cheapest bucket boundary and returns the number of
+    /// primitives on the left side, or `None` if keeping them in a single leaf is cheaper.
+    fn find_sah_split(primitives: &mut [BuildPrimitive], bounds: &Aabb) -> Option<usize> {
+        let centroid_bounds = Aabb::from_points(primitives.iter().map(|p| p.centroid));
+        let extent = centroid_bounds.max - centroid_bounds.min;
+
+        let bucket_of = |centroid: Vec3, axis: usize| -> usize {
+            let offset = (centroid[axis] - centroid_bounds.min[axis]) / extent[axis];
+            ((offset * SAH_BUCKETS as f32) as usize).min(SAH_BUCKETS - 1)
+        };
+
+        let mut best: Option<(usize, usize, f32)> = None;
+
+        for axis in 0..3 {
+            if extent[axis] <= f32::EPSILON {
+                continue;
+            }
+
+            let mut bucket_bounds = [Aabb::EMPTY; SAH_BUCKETS];
+            let mut bucket_counts = [0usize; SAH_BUCKETS];
+            for primitive in primitives.iter() {
+                let bucket = bucket_of(primitive.centroid, axis);
+                bucket_bounds[bucket] = bucket_bounds[bucket].union(primitive.bounds);
+                bucket_counts[bucket] += 1;
+            }
+
+            // Sweep from the right to know the cost of every right side in a single pass
+            let mut right_areas = [0.0; SAH_BUCKETS];
+            let mut right_counts = [0usize; SAH_BUCKETS];
+            let mut right_bounds = Aabb::EMPTY;
+            let mut right_count = 0;
+            for bucket in (1..SAH_BUCKETS).rev() {
+                right_bounds = right_bounds.union(bucket_bounds[bucket]);
+                right_count += bucket_counts[bucket];
+                right_areas[bucket] = right_bounds.surface_area();
+                right_counts[bucket] = right_count;
+            }
+
+            let mut left_bounds = Aabb::EMPTY;
+            let mut left_count = 0;
+            for bucket in 1..SAH_BUCKETS {
+                left_bounds = left_bounds.union(bucket_bounds[bucket - 1]);
+                left_count += bucket_counts[bucket - 1];
+
+                if left_count == 0 || right_counts[bucket] == 0 {
+                    continue;
+                }
+
+                let cost = left_bounds.surface_area() * left_count as f32
+                    + right_areas[bucket] * right_counts[bucket] as f32;
+                if best.is_none_or(|(_, _, best_cost)| cost < best_cost) {
+                    best = Some((axis, bucket, cost));
+                }
+            }
+        }
+
+        let (axis, bucket, cost) = best?;
+        let leaf_cost = primitives.len() as f32;
+        let split_cost = TRAVERSAL_COST + cost / bounds.surface_area().max(f32::EPSILON);
+        if split_cost >= leaf_cost {
+            return None;
+        }
+
+        let mut split = 0;
+        for index in 0..primitives.len() {
+            if bucket_of(primitives[index].centroid, axis) < bucket {
+                primitives.swap(index, split);
+                split += 1;
+            }
+        }
+
+        Some(split)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BvhTracer;
+    use crate::raytracer::tracer::Tracer;
+    use crate::raytracer::tracer::naive::NaiveTracer;
+    use crate::raytracer::world::{Geometry, GeometryType, Ray, Transform, TriangleMeshGeometry};
+    use glam::{Affine3A, Quat, Vec3};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::sync::Arc;
+
+    const PRIMITIVES: &str = r#"
+        [[geometry]]
+        type = "sphere"
+        center = [0.0, 0.0, 0.0]
+        radius = 1.0
+        material = "lambertian"
+        texture = "solid"
+        color = [0.8, 0.8, 0.8, 1.0]
+
+        [[geometry]]
+        type = "sphere"
+        center = [2.5, 1.0, -1.0]
+        radius = 0.5
+        material = "lambertian"
+        texture = "solid"
+        color = [0.8, 0.8, 0.8, 1.0]
+
+        [[geometry]]
+        type = "quad"
+        origin = [-4.0, -1.5, -4.0]
+        u = [8.0, 0.0, 0.0]
+        v = [0.0, 0.0, 8.0]
+        material = "lambertian"
+        texture = "solid"
+        color = [0.8, 0.8, 0.8, 1.0]
+
+        [[geometry]]
+        type = "box"
+        origin = [-3.0, -1.0, 1.0]
+        u = [1.0, 0.0, 0.5]
+        v = [0.0, 1.5, 0.0]
+        w = [-0.5, 0.0, 1.0]
+        material = "lambertian"
+        texture = "solid"
+        color = [0.8, 0.8, 0.8, 1.0]
+    "#;
+
+    #[derive(serde::Deserialize)]
+    struct Scene {
+        geometry: Vec<Geometry>,
+    }
+
+    /// Spheres, a quad and a box, plus a triangle soup placed once as is and once through a
+    /// transform, so it is traced as two instances of the same mesh.
+    fn mixed_scene(rng: &mut StdRng) -> Vec<Geometry> {
+        let mut geometry = toml::from_str::<Scene>(PRIMITIVES).unwrap().geometry;
+
+        let verts = (0..60)
+            .map(|_| {
+                let vert = Vec3::new(rng.random(), rng.random(), rng.random()) * 2.0;
+                (vert.x, vert.y, vert.z)
+            })
+            .collect();
+        let indices = (0..20).map(|i| (3 * i, 3 * i + 1, 3 * i + 2)).collect();
+        let mesh = Arc::new(TriangleMeshGeometry {
+            verts,
+            indices,
+            normals: Vec::new(),
+            tex_coords: Vec::new(),
+            colors: Vec::new(),
+        });
+
+        let mut mesh_geometry = geometry[0].clone();
+        mesh_geometry.geometry_type = GeometryType::TriangleMesh(mesh);
+        let mut instance = mesh_geometry.clone();
+        instance.transform = Transform::new(Affine3A::from_scale_rotation_translation(
+            Vec3::new(1.5, 0.5, 1.0),
+            Quat::from_rotation_y(0.7),
+            Vec3::new(-1.0, 1.0, -3.0),
+        ));
+        geometry.extend([mesh_geometry, instance]);
+        geometry
+    }
+
+    #[test]
+    fn matches_naive_tracer() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let geometry = mixed_scene(&mut rng);
+        let naive = NaiveTracer::build(&geometry);
+        let bvh = BvhTracer::build(&geometry);
+
+        let range = 1e-3..f32::INFINITY;
+        let mut hits = 0;
+        for _ in 0..10_000 {
+            let origin = Vec3::new(rng.random(), rng.random(), rng.random()) * 12.0 - 6.0;
+            let target = Vec3::new(rng.random(), rng.random(), rng.random()) * 6.0 - 3.0;
+            let ray = Ray::new(origin, (target - origin).normalize());
+
+            let expected = naive.trace(&ray, &range);
+            let actual = bvh.trace(&ray, &range);
+            match (&expected, &actual) {
+                (Some(expected), Some(actual)) => {
+                    hits += 1;
+                    assert_eq!(expected.geometry_index, actual.geometry_index);
+                    assert!((expected.distance - actual.distance).abs() < 1e-4);
+                }
+                (None, None) => {}
+                _ => panic!(
+                    "naive hit {}, BVH hit {}",
+                    expected.is_some(),
+                    actual.is_some()
+                ),
+            }
+            assert_eq!(naive.occluded(&ray, &range), bvh.occluded(&ray, &range));
+        }
+        assert!(hits > 1_000, "only {hits} rays hit the scene");
+    }
+}
@@ -1,133 +1,114 @@
-use crate::raytracer::tracer::TraceResult;
-use crate::raytracer::world::{Geometry, GeometryType, Ray};
-use embree4_rs::geometry::SphereGeometry;
-use embree4_sys::{RTCRay, RTCRayHit};
-use glam::Vec3;
-use std::collections::Bound;
-use std::ops::RangeBounds;
+use crate::raytracer::material::MaterialType;
+use crate::raytracer::tracer::{
+    SurfaceCoordinates, TraceResult, Tracer, TracerStats, box_local_point, instanced_geometry,
+};
+use crate::raytracer::world::{Aabb, Geometry, GeometryType, Ray, Transform};
+use embree4_rs::geometry::{Geometry as EmbreeGeometry, SphereGeometry};
+use embree4_sys::{
+    RTC_INVALID_GEOMETRY_ID, RTCFilterFunctionNArguments, RTCFormat, RTCGeometry, RTCGeometryType,
+    RTCHit, RTCRay, RTCRayHit, RTCScene, rtcCommitGeometry, rtcNewGeometry, rtcReleaseGeometry,
+    rtcSetGeometryInstancedScene, rtcSetGeometryIntersectFilterFunction,
+    rtcSetGeometryOccludedFilterFunction, rtcSetGeometryTransform, rtcSetGeometryUserData,
+};
+use glam::{Vec3, Vec4};
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::Instant;
 
 pub struct EmbreeTracer {
     committed_scene: embree4_rs::CommittedScene<'static>,
+    /// Transform of each geometry, to bring the hits on instances back to world space.
+    transforms: Vec<Transform>,
+    /// Shape of each geometry, to compute the texture coordinates of hits, since Embree only
+    /// reports the barycentric coordinates of triangles.
+    geometry_types: Vec<GeometryType>,
+    bounds: Aabb,
+    stats: TracerStats,
 }
 
-impl EmbreeTracer {
-    pub fn new(geometry: &[Geometry]) -> EmbreeTracer {
+impl Tracer for EmbreeTracer {
+    fn build(geometry: &[Geometry]) -> EmbreeTracer {
+        let instant = Instant::now();
         let device = embree4_rs::Device::try_new(None).expect("Failed to create Embree device");
         let device = Box::leak(Box::new(device));
 
-        let scene = embree4_rs::Scene::try_new(
-            device,
-            embree4_rs::SceneOptions {
-                build_quality: embree4_sys::RTCBuildQuality::HIGH,
-                flags: embree4_sys::RTCSceneFlags::ROBUST,
-            },
-        )
-        .expect("Failed to create Embree scene");
+        let options = || embree4_rs::SceneOptions {
+            build_quality: embree4_sys::RTCBuildQuality::HIGH,
+            flags: embree4_sys::RTCSceneFlags::ROBUST,
+        };
+        let scene =
+            embree4_rs::Scene::try_new(device, options()).expect("Failed to create Embree scene");
         let scene = Box::leak(Box::new(scene));
 
-        for geom in geometry {
-            match &geom.geometry_type {
-                GeometryType::Sphere { center, radius } => {
-                    let embree_geom =
-                        SphereGeometry::try_new(device, (center.x, center.y, center.z), *radius)
-                            .expect("Failed to create sphere geometry");
+        let instanced = instanced_geometry(geometry);
+        let mut instanced_scenes = HashMap::new();
 
-                    scene
-                        .attach_geometry(&embree_geom)
-                        .expect("Failed to attach sphere geometry");
-                }
-                GeometryType::Quad { origin, u, v } => {
-                    let vertices = [
-                        origin.clone().into(),
-                        (origin + u).into(),
-                        (origin + u + v).into(),
-                        (origin + v).into(),
-                    ];
-
-                    let indices = [(2, 1, 0), (0, 3, 2)];
-
-                    let embree_geom = embree4_rs::geometry::TriangleMeshGeometry::try_new(
-                        device, &vertices, &indices,
-                    )
-                    .expect("Failed to create quad geometry");
-
-                    scene
-                        .attach_geometry(&embree_geom)
-                        .expect("Failed to attach quad geometry");
-                }
-                GeometryType::TriangleMesh(mesh) => {
-                    let embree_geom = embree4_rs::geometry::TriangleMeshGeometry::try_new(
-                        device,
-                        &mesh.verts,
-                        &mesh.indices,
-                    )
-                    .expect("Failed to create triangle mesh geometry");
-
-                    scene
-                        .attach_geometry(&embree_geom)
-                        .expect("Failed to attach triangle mesh geometry");
-                }
-                GeometryType::Box { origin, u, v, w } => {
-                    // Convert oriented box to triangle mesh
-                    // Define 8 vertices of the oriented box
-                    let vertices = [
-                        (*origin).into(),                // 0: origin
-                        (*origin + *u).into(),           // 1: origin + u
-                        (*origin + *u + *v).into(),      // 2: origin + u + v
-                        (*origin + *v).into(),           // 3: origin + v
-                        (*origin + *w).into(),           // 4: origin + w
-                        (*origin + *u + *w).into(),      // 5: origin + u + w
-                        (*origin + *u + *v + *w).into(), // 6: origin + u + v + w
-                        (*origin + *v + *w).into(),      // 7: origin + v + w
-                    ];
-
-                    // Define 12 triangles (2 per face, 6 faces)
-                    #[rustfmt::skip]
-                    let indices = [
-                        // Bottom face (no w component) - normal pointing down (-v direction)
-                        (0, 1, 2), (0, 2, 3),
-                        // Top face (w component) - normal pointing up (+v direction)
-                        (4, 7, 6), (4, 6, 5),
-                        // Left face (no u component) - normal pointing left (-u direction)
-                        (0, 3, 7), (0, 7, 4),
-                        // Right face (u component) - normal pointing right (+u direction)
-                        (1, 5, 6), (1, 6, 2),
-                        // Front face (no v component) - normal pointing down (-v direction)
-                        (0, 4, 5), (0, 5, 1),
-                        // Back face (v component) - normal pointing up (+v direction)
-                        (3, 2, 6), (3, 6, 7),
-                    ];
-
-                    let embree_geom = embree4_rs::geometry::TriangleMeshGeometry::try_new(
-                        device, &vertices, &indices,
-                    )
-                    .expect("Failed to create box geometry");
-
-                    scene
-                        .attach_geometry(&embree_geom)
-                        .expect("Failed to attach box geometry");
-                }
+        for (index, geom) in geometry.iter().enumerate() {
+            if !instanced[index] {
+                Self::attach_geometry(device, scene, geom);
+                continue;
             }
+
+            // Instanced geometry lives in its own scene, built once for each distinct mesh
+            // unless alpha masked, as the mask comes from the material of each instance
+            let build_scene = || {
+                let instanced_scene = embree4_rs::Scene::try_new(device, options())
+                    .expect("Failed to create Embree scene");
+                let instanced_scene = Box::leak(Box::new(instanced_scene));
+                Self::attach_geometry(device, instanced_scene, geom);
+                let committed_scene = instanced_scene.commit().expect("Failed to commit scene");
+                Box::leak(Box::new(committed_scene)).handle()
+            };
+            let instanced_scene = match &geom.geometry_type {
+                GeometryType::TriangleMesh(mesh) if !geom.material.has_alpha_mask() => {
+                    *instanced_scenes
+                        .entry(Arc::as_ptr(mesh))
+                        .or_insert_with(build_scene)
+                }
+                _ => build_scene(),
+            };
+
+            let instance = InstanceGeometry::try_new(device, instanced_scene, &geom.transform)
+                .expect("Failed to create instance geometry");
+            scene
+                .attach_geometry(&instance)
+                .expect("Failed to attach instance geometry");
         }
 
         let committed_scene = scene.commit().expect("Failed to commit scene");
 
-        EmbreeTracer { committed_scene }
-    }
+        let bounds = geometry
+            .iter()
+            .map(Geometry::bounds)
+            .fold(Aabb::EMPTY, Aabb::union);
 
-    pub fn trace(&self, ray: &Ray, ray_bounds: &impl RangeBounds<f32>) -> Option<TraceResult> {
-        let tnear = match ray_bounds.start_bound() {
-            Bound::Included(&v) => v,
-            Bound::Excluded(&v) => v,
-            Bound::Unbounded => 0.0,
-        };
+        let primitive_count = geometry
+            .iter()
+            .map(|geom| match &geom.geometry_type {
+                GeometryType::Sphere { .. } => 1,
+                GeometryType::Quad { .. } => 2,
+                GeometryType::TriangleMesh(mesh) => mesh.indices.len(),
+                GeometryType::Box { .. } => 12,
+            })
+            .sum();
 
-        let tfar = match ray_bounds.end_bound() {
-            Bound::Included(&v) => v,
-            Bound::Excluded(&v) => v,
-            Bound::Unbounded => f32::INFINITY,
-        };
+        EmbreeTracer {
+            committed_scene,
+            transforms: geometry.iter().map(|geom| geom.transform).collect(),
+            geometry_types: geometry
+                .iter()
+                .map(|geom| geom.geometry_type.clone())
+                .collect(),
+            bounds,
+            stats: TracerStats {
+                primitive_count,
+                build_time: instant.elapsed(),
+            },
+        }
+    }
 
+    fn trace(&self, ray: &Ray, range: &Range<f32>) -> Option<TraceResult> {
         self.committed_scene
             .intersect_1(RTCRay {
                 org_x: ray.origin.x,
@@ -136,12 +117,284 @@ impl EmbreeTracer {
                 dir_x: ray.direction.x,
                 dir_y: ray.direction.y,
                 dir_z: ray.direction.z,
-                tnear,
-                tfar,
+                tnear: range.start,
+                tfar: range.end,
                 ..Default::default()
             })
             .expect("Device error while intersecting ray")
-            .map(Into::into)
+            .map(|mut ray_hit| {
+                let instance_id = ray_hit.hit.instID[0];
+                if instance_id != RTC_INVALID_GEOMETRY_ID {
+                    // Embree reports the geometry and normal of instanced hits in object space
+                    let transform = &self.transforms[instance_id as usize];
+                    let normal = transform.normal_to_world(Vec3::new(
+                        ray_hit.hit.Ng_x,
+                        ray_hit.hit.Ng_y,
+                        ray_hit.hit.Ng_z,
+                    ));
+                    ray_hit.hit.Ng_x = normal.x;
+                    ray_hit.hit.Ng_y = normal.y;
+                    ray_hit.hit.Ng_z = normal.z;
+                    ray_hit.hit.geomID = instance_id;
+                }
+
+                let geometry_index = ray_hit.hit.geomID as usize;
+                surface_hit(
+                    &self.geometry_types[geometry_index],
+                    &self.transforms[geometry_index],
+                    ray_hit,
+                )
+            })
+    }
+
+    fn occluded(&self, ray: &Ray, range: &Range<f32>) -> bool {
+        // Uses rtcOccluded1, which stops at the first hit found
+        self.committed_scene
+            .occluded_1(RTCRay {
+                org_x: ray.origin.x,
+                org_y: ray.origin.y,
+                org_z: ray.origin.z,
+                dir_x: ray.direction.x,
+                dir_y: ray.direction.y,
+                dir_z: ray.direction.z,
+                tnear: range.start,
+                tfar: range.end,
+                ..Default::default()
+            })
+            .expect("Device error while checking occlusion")
+    }
+
+    fn bounds(&self) -> Aabb {
+        self.bounds
+    }
+
+    fn stats(&self) -> TracerStats {
+        self.stats
+    }
+}
+
+impl EmbreeTracer {
+    fn attach_geometry(
+        device: &embree4_rs::Device,
+        scene: &mut embree4_rs::Scene,
+        geometry: &Geometry,
+    ) {
+        match &geometry.geometry_type {
+            GeometryType::Sphere { center, radius } => {
+                let embree_geom =
+                    SphereGeometry::try_new(device, (center.x, center.y, center.z), *radius)
+                        .expect("Failed to create sphere geometry");
+
+                set_alpha_mask(&embree_geom, geometry);
+                scene
+                    .attach_geometry(&embree_geom)
+                    .expect("Failed to attach sphere geometry");
+            }
+            GeometryType::Quad { origin, u, v } => {
+                let vertices = [
+                    origin.clone().into(),
+                    (origin + u).into(),
+                    (origin + u + v).into(),
+                    (origin + v).into(),
+                ];
+
+                let indices = [(2, 1, 0), (0, 3, 2)];
+
+                let embree_geom = embree4_rs::geometry::TriangleMeshGeometry::try_new(
+                    device, &vertices, &indices,
+                )
+                .expect("Failed to create quad geometry");
+
+                set_alpha_mask(&embree_geom, geometry);
+                scene
+                    .attach_geometry(&embree_geom)
+                    .expect("Failed to attach quad geometry");
+            }
+            GeometryType::TriangleMesh(mesh) => {
+                let embree_geom = embree4_rs::geometry::TriangleMeshGeometry::try_new(
+                    device,
+                    &mesh.verts,
+                    &mesh.indices,
+                )
+                .expect("Failed to create triangle mesh geometry");
+
+                set_alpha_mask(&embree_geom, geometry);
+                scene
+                    .attach_geometry(&embree_geom)
+                    .expect("Failed to attach triangle mesh geometry");
+            }
+            GeometryType::Box { origin, u, v, w } => {
+                // Convert oriented box to triangle mesh
+                // Define 8 vertices of the oriented box
+                let vertices = [
+                    (*origin).into(),                // 0: origin
+                    (*origin + *u).into(),           // 1: origin + u
+                    (*origin + *u + *v).into(),      // 2: origin + u + v
+                    (*origin + *v).into(),           // 3: origin + v
+                    (*origin + *w).into(),           // 4: origin + w
+                    (*origin + *u + *w).into(),      // 5: origin + u + w
+                    (*origin + *u + *v + *w).into(), // 6: origin + u + v + w
+                    (*origin + *v + *w).into(),      // 7: origin + v + w
+                ];
+
+                // Define 12 triangles (2 per face, 6 faces)
+                #[rustfmt::skip]
+                let indices = [
+                    // Bottom face (no w component) - normal pointing down (-v direction)
+                    (0, 1, 2), (0, 2, 3),
+                    // Top face (w component) - normal pointing up (+v direction)
+                    (4, 7, 6), (4, 6, 5),
+                    // Left face (no u component) - normal pointing left (-u direction)
+                    (0, 3, 7), (0, 7, 4),
+                    // Right face (u component) - normal pointing right (+u direction)
+                    (1, 5, 6), (1, 6, 2),
+                    // Front face (no v component) - normal pointing down (-v direction)
+                    (0, 4, 5), (0, 5, 1),
+                    // Back face (v component) - normal pointing up (+v direction)
+                    (3, 2, 6), (3, 6, 7),
+                ];
+
+                let embree_geom = embree4_rs::geometry::TriangleMeshGeometry::try_new(
+                    device, &vertices, &indices,
+                )
+                .expect("Failed to create box geometry");
+
+                set_alpha_mask(&embree_geom, geometry);
+                scene
+                    .attach_geometry(&embree_geom)
+                    .expect("Failed to attach box geometry");
+            }
+        }
+    }
+}
+
+/// Converts an Embree hit on a geometry of `geometry_type` placed with `transform`, computing
+/// its texture coordinates and tangents from the shape.
+fn surface_hit(
+    geometry_type: &GeometryType,
+    transform: &Transform,
+    ray_hit: RTCRayHit,
+) -> TraceResult {
+    let triangle = ray_hit.hit.primID as usize;
+    let mut result: TraceResult = ray_hit.into();
+    let object_point = transform.world_to_object.transform_point3(result.point);
+    let coordinates = match geometry_type {
+        GeometryType::TriangleMesh(mesh) => {
+            result.interpolate_mesh_attributes(mesh, triangle, transform);
+            return result;
+        }
+        GeometryType::Sphere { center, radius } => {
+            SurfaceCoordinates::on_sphere(*center, *radius, object_point)
+        }
+        GeometryType::Quad { origin, u, v } => {
+            SurfaceCoordinates::on_quad(*origin, *u, *v, object_point)
+        }
+        GeometryType::Box { origin, u, v, w } => SurfaceCoordinates::on_box(
+            *u,
+            *v,
+            *w,
+            box_local_point(*origin, *u, *v, *w, object_point),
+        ),
+    };
+    result.uv = coordinates.uv;
+    result.tangent = transform.vector_to_world(coordinates.tangent);
+    result.bitangent = transform.vector_to_world(coordinates.bitangent);
+    result
+}
+
+/// Material and shape of a geometry with an alpha mask, read by [`alpha_mask_filter`].
+struct AlphaMask {
+    material: MaterialType,
+    geometry_type: GeometryType,
+}
+
+/// Makes Embree skip the hits in the holes of the alpha mask of the geometry, if it has one.
+fn set_alpha_mask(embree_geom: &impl EmbreeGeometry, geometry: &Geometry) {
+    if !geometry.material.has_alpha_mask() {
+        return;
+    }
+
+    // Leaked like the scenes, which keep pointing to it
+    let mask: &'static mut AlphaMask = Box::leak(Box::new(AlphaMask {
+        material: geometry.material.clone(),
+        geometry_type: geometry.geometry_type.clone(),
+    }));
+
+    // SAFETY: the geometry handle is valid and the mask lives as long as the program
+    unsafe {
+        let handle = embree_geom.geometry();
+        rtcSetGeometryUserData(handle, (mask as *mut AlphaMask).cast());
+        rtcSetGeometryIntersectFilterFunction(handle, Some(alpha_mask_filter));
+        rtcSetGeometryOccludedFilterFunction(handle, Some(alpha_mask_filter));
+        rtcCommitGeometry(handle);
+    }
+}
+
+/// Filter rejecting the hits the material lets rays through. Embree calls it with the ray in
+/// the object space of instances, where the geometry is described.
+unsafe extern "C" fn alpha_mask_filter(args: *const RTCFilterFunctionNArguments) {
+    // SAFETY: Embree passes valid arguments, holding a single ray and hit since rays are
+    // traced one at a time, and the user data was set by `set_alpha_mask`
+    unsafe {
+        let args = &*args;
+        if args.N != 1 {
+            return;
+        }
+
+        let mask = &*args.geometryUserPtr.cast::<AlphaMask>();
+        let ray_hit = RTCRayHit {
+            ray: *args.ray.cast::<RTCRay>(),
+            hit: *args.hit.cast::<RTCHit>(),
+        };
+        let hit = surface_hit(&mask.geometry_type, &Transform::IDENTITY, ray_hit);
+        if !mask.material.is_opaque_at(&hit) {
+            *args.valid = 0;
+        }
+    }
+}
+
+/// Instance of a committed scene placed with a transform, which embree4-rs doesn't wrap.
+struct InstanceGeometry {
+    handle: RTCGeometry,
+}
+
+impl InstanceGeometry {
+    fn try_new(
+        device: &embree4_rs::Device,
+        scene: RTCScene,
+        transform: &Transform,
+    ) -> anyhow::Result<Self> {
+        let matrix = transform.object_to_world.to_cols_array();
+
+        // SAFETY: the device and scene handles are valid, and Embree copies the matrix
+        unsafe {
+            let handle = rtcNewGeometry(device.handle(), RTCGeometryType::INSTANCE);
+            if handle.is_null() {
+                anyhow::bail!("rtcNewGeometry returned null");
+            }
+            rtcSetGeometryInstancedScene(handle, scene);
+            rtcSetGeometryTransform(
+                handle,
+                0,
+                RTCFormat::FLOAT3X4_COLUMN_MAJOR,
+                matrix.as_ptr().cast(),
+            );
+            rtcCommitGeometry(handle);
+            Ok(Self { handle })
+        }
+    }
+}
+
+impl EmbreeGeometry for InstanceGeometry {
+    fn geometry(&self) -> RTCGeometry {
+        self.handle
+    }
+}
+
+impl Drop for InstanceGeometry {
+    fn drop(&mut self) {
+        // SAFETY: the handle was created by rtcNewGeometry and is only released here
+        unsafe { rtcReleaseGeometry(self.handle) }
     }
 }
 
@@ -162,7 +415,9 @@ impl From<Ray> for RTCRay {
 impl From<RTCRayHit> for TraceResult {
     fn from(value: RTCRayHit) -> Self {
         let origin = Vec3::new(value.ray.org_x, value.ray.org_y, value.ray.org_z);
-        let dir = Vec3::new(value.ray.dir_x, value.ray.dir_y, value.ray.dir_z).normalize();
+        // Distances are in units of the direction, which isn't normalized in the object space
+        // of scaled instances
+        let dir = Vec3::new(value.ray.dir_x, value.ray.dir_y, value.ray.dir_z);
         let point = origin + dir * value.ray.tfar;
 
         let mut normal = Vec3::new(value.hit.Ng_x, value.hit.Ng_y, value.hit.Ng_z).normalize();
@@ -175,10 +430,15 @@ impl From<RTCRayHit> for TraceResult {
         TraceResult {
             distance: value.ray.tfar,
             normal,
+            shading_normal: normal,
+            tangent: Vec3::ZERO,
+            bitangent: Vec3::ZERO,
+            vertex_color: Vec4::ONE,
             front_face,
             geometry_index: value.hit.geomID as usize,
             point,
             uv: (value.hit.u, value.hit.v),
+            uv_derivatives: None,
         }
     }
 }
@@ -1,31 +1,254 @@
+pub mod bvh;
 pub mod embree;
 pub mod naive;
 
+use crate::raytracer::tracer::bvh::BvhTracer;
 use crate::raytracer::tracer::embree::EmbreeTracer;
 use crate::raytracer::tracer::naive::NaiveTracer;
-use crate::raytracer::world::Ray;
-use glam::Vec3;
-use std::ops::RangeBounds;
+use crate::raytracer::world::{
+    Aabb, Geometry, GeometryType, Ray, RayDifferentials, Transform, TriangleMeshGeometry,
+};
+use glam::{Mat3, Vec2, Vec3, Vec4};
+use std::collections::HashMap;
+use std::f32::consts::PI;
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::Duration;
 
+#[derive(Clone)]
 pub struct TraceResult {
     pub distance: f32,
     pub point: Vec3,
+    /// Normal of the surface that was hit, facing against the ray.
     pub normal: Vec3,
+    /// Normal used to shade the hit, interpolated from the vertex normals of meshes.
+    /// Always on the same side as `normal`, which should still be used to offset rays.
+    pub shading_normal: Vec3,
+    /// Derivatives of the hit point along the u and v texture coordinates, spanning the
+    /// tangent frame of normal and bump maps. Neither normalized nor orthogonal.
+    pub tangent: Vec3,
+    pub bitangent: Vec3,
+    /// Interpolated vertex color of meshes, white for every other hit.
+    pub vertex_color: Vec4,
     pub geometry_index: usize,
     pub front_face: bool,
     pub uv: (f32, f32),
+    /// Change of the texture coordinates from one pixel to the next, along x and y, or `None`
+    /// when the ray has no differentials.
+    pub uv_derivatives: Option<(Vec2, Vec2)>,
 }
 
-pub enum Tracer {
-    NaiveTracer(NaiveTracer),
-    EmbreeTracer(EmbreeTracer),
+impl TraceResult {
+    /// Fills in the texture coordinate derivatives of the hit from where the offset rays of
+    /// `ray` cross its tangent plane, as the steps along the tangents closest to the offsets.
+    pub fn set_uv_derivatives(&mut self, ray: &Ray) {
+        self.uv_derivatives = ray
+            .differentials
+            .and_then(|differentials| self.solve_uv_derivatives(&differentials));
+    }
+
+    fn solve_uv_derivatives(&self, differentials: &RayDifferentials) -> Option<(Vec2, Vec2)> {
+        let (dpdx, dpdy) = differentials.plane_offsets(self.point, self.normal)?;
+        let (dpdu, dpdv) = (self.tangent, self.bitangent);
+        let (uu, uv, vv) = (dpdu.dot(dpdu), dpdu.dot(dpdv), dpdv.dot(dpdv));
+        let determinant = uu * vv - uv * uv;
+        if determinant.abs() < 1e-12 {
+            return None;
+        }
+
+        let solve = |dp: Vec3| {
+            let (pu, pv) = (dp.dot(dpdu), dp.dot(dpdv));
+            Vec2::new(vv * pu - uv * pv, uu * pv - uv * pu) / determinant
+        };
+        let derivatives = (solve(dpdx), solve(dpdy));
+        (derivatives.0.is_finite() && derivatives.1.is_finite()).then_some(derivatives)
+    }
+
+    /// Replaces the barycentric coordinates of a hit on `triangle` of `mesh` with the
+    /// interpolated texture coordinates, tangents, shading normal and vertex color. `transform`
+    /// brings the tangents and shading normal from the mesh to the space of the hit.
+    fn interpolate_mesh_attributes(
+        &mut self,
+        mesh: &TriangleMeshGeometry,
+        triangle: usize,
+        transform: &Transform,
+    ) {
+        let barycentric = self.uv;
+        self.uv = mesh.tex_coord(triangle, barycentric);
+        let (tangent, bitangent) = mesh.tangents(triangle);
+        self.tangent = transform.vector_to_world(tangent);
+        self.bitangent = transform.vector_to_world(bitangent);
+        if let Some(color) = mesh.vertex_color(triangle, barycentric) {
+            self.vertex_color = color.extend(1.0);
+        }
+
+        if let Some(normal) = mesh.shading_normal(triangle, barycentric) {
+            let normal = transform.normal_to_world(normal);
+            self.shading_normal = if normal.dot(self.normal) < 0.0 {
+                -normal
+            } else {
+                normal
+            };
+        }
+    }
+}
+
+/// Texture coordinates of a point on a surface, with the derivatives of the point along them.
+pub(super) struct SurfaceCoordinates {
+    pub uv: (f32, f32),
+    pub tangent: Vec3,
+    pub bitangent: Vec3,
+}
+
+impl SurfaceCoordinates {
+    /// Longitude and latitude, with v going from the bottom pole to the top one.
+    pub(super) fn on_sphere(center: Vec3, radius: f32, point: Vec3) -> Self {
+        let local = ((point - center) / radius).clamp(Vec3::NEG_ONE, Vec3::ONE);
+        let theta = (-local.y).acos();
+        let phi = (-local.z).atan2(local.x) + PI;
+
+        // Distance to the axis, kept away from zero so the poles still get a frame
+        let rho = (local.x * local.x + local.z * local.z).sqrt().max(1e-6);
+        Self {
+            uv: (phi / (2.0 * PI), theta / PI),
+            tangent: 2.0 * PI * radius * Vec3::new(local.z, 0.0, -local.x),
+            bitangent: PI
+                * radius
+                * Vec3::new(-local.x * local.y / rho, rho, -local.z * local.y / rho),
+        }
+    }
+
+    pub(super) fn on_quad(origin: Vec3, u: Vec3, v: Vec3, point: Vec3) -> Self {
+        let offset = point - origin;
+        Self {
+            uv: (
+                offset.dot(u) / u.length_squared(),
+                offset.dot(v) / v.length_squared(),
+            ),
+            tangent: u,
+            bitangent: v,
+        }
+    }
+
+    /// Each face is mapped on its own, from the point in box space where the box spans [0, 1]³.
+    pub(super) fn on_box(u: Vec3, v: Vec3, w: Vec3, local_point: Vec3) -> Self {
+        let face = box_face(local_point);
+        let (uv, tangent, bitangent) = if face.x != 0.0 {
+            ((local_point.z, local_point.y), w, v)
+        } else if face.y != 0.0 {
+            ((local_point.x, local_point.z), u, w)
+        } else {
+            ((local_point.x, local_point.y), u, v)
+        };
+        Self {
+            uv,
+            tangent,
+            bitangent,
+        }
+    }
 }
 
-impl Tracer {
-    pub fn trace(&self, ray: &Ray, bounds: &impl RangeBounds<f32>) -> Option<TraceResult> {
-        match self {
-            Tracer::NaiveTracer(tracer) => tracer.trace(ray, bounds),
-            Tracer::EmbreeTracer(tracer) => tracer.trace(ray, bounds),
+/// Normal in box space of the face closest to a point of a box spanning [0, 1]³.
+pub(super) fn box_face(local_point: Vec3) -> Vec3 {
+    let distances = [
+        local_point.x.abs(),
+        (local_point.x - 1.0).abs(),
+        local_point.y.abs(),
+        (local_point.y - 1.0).abs(),
+        local_point.z.abs(),
+        (local_point.z - 1.0).abs(),
+    ];
+    let closest = (0..distances.len())
+        .min_by(|&a, &b| distances[a].total_cmp(&distances[b]))
+        .unwrap_or(0);
+    let axis = Vec3::AXES[closest / 2];
+    if closest % 2 == 0 { -axis } else { axis }
+}
+
+/// Point of a box spanning [0, 1]³ along `u`, `v` and `w` from `origin`.
+pub(super) fn box_local_point(origin: Vec3, u: Vec3, v: Vec3, w: Vec3, point: Vec3) -> Vec3 {
+    Mat3::from_cols(u, v, w).inverse() * (point - origin)
+}
+
+/// An acceleration structure that answers ray queries against the world geometry.
+///
+/// To add a new backend, implement this trait and register it in [`BACKENDS`].
+pub trait Tracer: Send + Sync {
+    fn build(geometry: &[Geometry]) -> Self
+    where
+        Self: Sized;
+
+    /// Finds the closest hit along the ray whose distance is inside `range`.
+    fn trace(&self, ray: &Ray, range: &Range<f32>) -> Option<TraceResult>;
+
+    /// Checks if anything is hit along the ray inside `range`, without computing the hit
+    /// details. Used for visibility tests such as shadow rays.
+    fn occluded(&self, ray: &Ray, range: &Range<f32>) -> bool;
+
+    fn bounds(&self) -> Aabb;
+
+    fn stats(&self) -> TracerStats;
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TracerStats {
+    /// Number of primitives the tracer intersects against.
+    pub primitive_count: usize,
+    pub build_time: Duration,
+}
+
+#[derive(Debug)]
+pub struct TracerBackend {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub build: fn(&[Geometry]) -> Box<dyn Tracer>,
+}
+
+impl TracerBackend {
+    const fn new<T: Tracer + 'static>(name: &'static str, description: &'static str) -> Self {
+        fn build<T: Tracer + 'static>(geometry: &[Geometry]) -> Box<dyn Tracer> {
+            Box::new(T::build(geometry))
+        }
+
+        Self {
+            name,
+            description,
+            build: build::<T>,
+        }
+    }
+}
+
+/// Every tracer that can be selected from the command line.
+pub const BACKENDS: &[TracerBackend] = &[
+    TracerBackend::new::<EmbreeTracer>("embree", "Use the Embree ray tracer implementation"),
+    TracerBackend::new::<NaiveTracer>("naive", "Use the naive ray tracer implementation"),
+    TracerBackend::new::<BvhTracer>(
+        "bvh",
+        "Use the pure Rust bounding volume hierarchy implementation",
+    ),
+];
+
+/// Whether each geometry should be traced as an instance in its own object space: when it
+/// has a transform, or when its mesh is shared with other geometry, so it is only stored once.
+fn instanced_geometry(geometry: &[Geometry]) -> Vec<bool> {
+    let mut mesh_uses = HashMap::new();
+    for geom in geometry {
+        if let GeometryType::TriangleMesh(mesh) = &geom.geometry_type {
+            *mesh_uses.entry(Arc::as_ptr(mesh)).or_insert(0) += 1;
         }
     }
+
+    geometry
+        .iter()
+        .map(|geom| match &geom.geometry_type {
+            GeometryType::TriangleMesh(mesh) => {
+                !geom.transform.is_identity() || mesh_uses[&Arc::as_ptr(mesh)] > 1
+            }
+            _ => !geom.transform.is_identity(),
+        })
+        .collect()
+}
+
+pub fn backend(name: &str) -> Option<&'static TracerBackend> {
+    BACKENDS.iter().find(|backend| backend.name == name)
 }
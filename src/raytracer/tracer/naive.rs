@@ -1,18 +1,162 @@
-use crate::raytracer::tracer::TraceResult;
-use crate::raytracer::world::{Geometry, GeometryType, Ray};
-use glam::Vec3;
-use std::f32::consts::PI;
-use std::ops::RangeBounds;
+use crate::raytracer::material::MaterialType;
+use crate::raytracer::tracer::{
+    SurfaceCoordinates, TraceResult, Tracer, TracerStats, box_face, instanced_geometry,
+};
+use crate::raytracer::world::{Aabb, Geometry, GeometryType, Ray, Transform, TriangleMeshGeometry};
+use glam::{Vec3, Vec4};
+use std::collections::HashMap;
+use std::ops::{Range, RangeBounds};
+use std::sync::Arc;
+use std::time::Instant;
 
 pub struct NaiveTracer {
     objects: Vec<NaiveObject>,
+    bounds: Aabb,
+    stats: TracerStats,
 }
 
-impl NaiveTracer {
-    pub fn new(geometry: &[Geometry]) -> Self {
+impl Tracer for NaiveTracer {
+    fn build(geometry: &[Geometry]) -> Self {
+        let instant = Instant::now();
+        let objects =
+            NaiveObject::from_geometry(geometry, |geometry| Box::new(NaiveTracer::build(geometry)));
+
+        let bounds = geometry
+            .iter()
+            .map(Geometry::bounds)
+            .fold(Aabb::EMPTY, Aabb::union);
+
+        let stats = TracerStats {
+            primitive_count: objects.iter().map(NaiveObject::primitive_count).sum(),
+            build_time: instant.elapsed(),
+        };
+
+        Self {
+            objects,
+            bounds,
+            stats,
+        }
+    }
+
+    fn trace(&self, ray: &Ray, range: &Range<f32>) -> Option<TraceResult> {
+        let mut closest_hit: Option<TraceResult> = None;
+        for object in self.objects.iter() {
+            if let Some(hit) = object.hit(object.geometry_index, ray, range) {
+                if let Some(ref mut closest_hit) = closest_hit {
+                    if hit.distance < closest_hit.distance {
+                        *closest_hit = hit;
+                    }
+                } else {
+                    closest_hit = Some(hit);
+                }
+            }
+        }
+        closest_hit
+    }
+
+    fn occluded(&self, ray: &Ray, range: &Range<f32>) -> bool {
+        self.objects
+            .iter()
+            .any(|object| object.occludes(ray, range))
+    }
+
+    fn bounds(&self) -> Aabb {
+        self.bounds
+    }
+
+    fn stats(&self) -> TracerStats {
+        self.stats
+    }
+}
+
+pub(super) struct NaiveObject {
+    pub(super) geometry_index: usize,
+    geometry: NaiveGeometry,
+    /// Material of the geometry when it has an alpha mask, shared by all of its primitives.
+    mask: Option<Arc<MaterialType>>,
+}
+
+enum NaiveGeometry {
+    Sphere {
+        center: Vec3,
+        radius: f32,
+    },
+    Quad {
+        origin: Vec3,
+        u: Vec3,
+        v: Vec3,
+        normal: Vec3,
+        d: f32,
+    },
+    Triangle {
+        p1: Vec3,
+        p2: Vec3,
+        p3: Vec3,
+        /// Mesh the triangle comes from, to interpolate its vertex attributes.
+        mesh: Arc<TriangleMeshGeometry>,
+        triangle: usize,
+    },
+    Box {
+        origin: Vec3,
+        u: Vec3,
+        v: Vec3,
+        w: Vec3,
+    },
+    /// Geometry traced in its own object space, shared by every instance of the same mesh.
+    Instance {
+        transform: Transform,
+        tracer: Arc<dyn Tracer>,
+    },
+}
+
+impl NaiveObject {
+    /// Splits the world geometry into the primitives intersected by the tracer.
+    /// Instanced geometry becomes a single object that traces into the tracer made by
+    /// `build_instance`, built once for each distinct mesh. Alpha masked instances get their
+    /// own, since the mask comes from the material of each instance.
+    pub(super) fn from_geometry(
+        geometry: &[Geometry],
+        build_instance: fn(&[Geometry]) -> Box<dyn Tracer>,
+    ) -> Vec<NaiveObject> {
         let mut objects = Vec::new();
+        let mut instance_tracers: HashMap<*const TriangleMeshGeometry, Arc<dyn Tracer>> =
+            HashMap::new();
+        let instanced = instanced_geometry(geometry);
 
         for (index, geom) in geometry.iter().enumerate() {
+            if instanced[index] {
+                let object_geometry = Geometry {
+                    transform: Transform::IDENTITY,
+                    ..geom.clone()
+                };
+                let build = || Arc::from(build_instance(&[object_geometry]));
+                let tracer = match &geom.geometry_type {
+                    GeometryType::TriangleMesh(mesh) if !geom.material.has_alpha_mask() => {
+                        instance_tracers
+                            .entry(Arc::as_ptr(mesh))
+                            .or_insert_with(build)
+                            .clone()
+                    }
+                    _ => build(),
+                };
+
+                // The tracer of the instance applies the mask itself
+                objects.push(NaiveObject {
+                    geometry_index: index,
+                    geometry: NaiveGeometry::Instance {
+                        transform: geom.transform,
+                        tracer,
+                    },
+                    mask: None,
+                });
+                continue;
+            }
+
+            let mask = geom
+                .material
+                .has_alpha_mask()
+                .then(|| Arc::new(geom.material.clone()));
+
             match &geom.geometry_type {
                 GeometryType::Sphere { center, radius } => {
                     objects.push(NaiveObject {
@@ -21,6 +165,7 @@ impl NaiveTracer {
                             center: *center,
                             radius: *radius,
                         },
+                        mask: mask.clone(),
                     });
                 }
                 GeometryType::Quad { origin, u, v } => {
@@ -36,17 +181,25 @@ impl NaiveTracer {
                             normal,
                             d,
                         },
+                        mask: mask.clone(),
                     });
                 }
                 GeometryType::TriangleMesh(mesh) => {
-                    for (v1, v2, v3) in mesh.indices.iter() {
+                    for (triangle, (v1, v2, v3)) in mesh.indices.iter().enumerate() {
                         let p1 = mesh.verts[*v1 as usize].into();
                         let p2 = mesh.verts[*v2 as usize].into();
                         let p3 = mesh.verts[*v3 as usize].into();
 
                         objects.push(NaiveObject {
                             geometry_index: index,
-                            geometry: NaiveGeometry::Triangle { p1, p2, p3 },
+                            geometry: NaiveGeometry::Triangle {
+                                p1,
+                                p2,
+                                p3,
+                                mesh: mesh.clone(),
+                                triangle,
+                            },
+                            mask: mask.clone(),
                         });
                     }
                 }
@@ -59,70 +212,70 @@ impl NaiveTracer {
                             v: *v,
                             w: *w,
                         },
+                        mask: mask.clone(),
                     });
                 }
             }
         }
 
-        Self { objects }
+        objects
     }
-}
 
-impl NaiveTracer {
-    pub fn trace(&self, ray: &Ray, range: &impl RangeBounds<f32>) -> Option<TraceResult> {
-        let mut closest_hit: Option<TraceResult> = None;
-        for object in self.objects.iter() {
-            if let Some(hit) = object.hit(object.geometry_index, ray, range) {
-                if let Some(ref mut closest_hit) = closest_hit {
-                    if hit.distance < closest_hit.distance {
-                        *closest_hit = hit;
-                    }
-                } else {
-                    closest_hit = Some(hit);
-                }
+    pub(super) fn bounds(&self) -> Aabb {
+        match &self.geometry {
+            NaiveGeometry::Sphere { center, radius } => Aabb {
+                min: center - Vec3::splat(*radius),
+                max: center + Vec3::splat(*radius),
+            },
+            NaiveGeometry::Quad { origin, u, v, .. } => {
+                Aabb::from_points([*origin, origin + u, origin + v, origin + u + v])
+            }
+            NaiveGeometry::Triangle { p1, p2, p3, .. } => Aabb::from_points([*p1, *p2, *p3]),
+            NaiveGeometry::Box { origin, u, v, w } => Aabb::from_points([
+                *origin,
+                origin + u,
+                origin + v,
+                origin + w,
+                origin + u + v,
+                origin + u + w,
+                origin + v + w,
+                origin + u + v + w,
+            ]),
+            NaiveGeometry::Instance { transform, tracer } => {
+                transform.transform_aabb(tracer.bounds())
             }
         }
-        closest_hit
     }
-}
 
-struct NaiveObject {
-    geometry_index: usize,
-    geometry: NaiveGeometry,
-}
-
-enum NaiveGeometry {
-    Sphere {
-        center: Vec3,
-        radius: f32,
-    },
-    Quad {
-        origin: Vec3,
-        u: Vec3,
-        v: Vec3,
-        normal: Vec3,
-        d: f32,
-    },
-    Triangle {
-        p1: Vec3,
-        p2: Vec3,
-        p3: Vec3,
-    },
-    Box {
-        origin: Vec3,
-        u: Vec3,
-        v: Vec3,
-        w: Vec3,
-    },
-}
+    pub(super) fn primitive_count(&self) -> usize {
+        match &self.geometry {
+            NaiveGeometry::Instance { tracer, .. } => tracer.stats().primitive_count,
+            _ => 1,
+        }
+    }
 
-impl NaiveObject {
-    fn hit(
+    pub(super) fn hit(
         &self,
         my_index: usize,
         ray: &Ray,
-        range: &impl RangeBounds<f32>,
+        range: &Range<f32>,
     ) -> Option<TraceResult> {
+        let Some(material) = &self.mask else {
+            return self.hit_surface(my_index, ray, range);
+        };
+
+        // Look further along the ray until a hit isn't in a hole of the mask
+        let mut range = range.clone();
+        loop {
+            let hit = self.hit_surface(my_index, ray, &range)?;
+            if material.is_opaque_at(&hit) {
+                return Some(hit);
+            }
+            range.start = hit.distance.next_up();
+        }
+    }
+
+    fn hit_surface(&self, my_index: usize, ray: &Ray, range: &Range<f32>) -> Option<TraceResult> {
         match &self.geometry {
             NaiveGeometry::Sphere { center, radius } => {
                 Self::intersect_sphere(*center, *radius, my_index, ray, range)
@@ -134,22 +287,69 @@ impl NaiveObject {
                 normal,
                 d,
             } => Self::intersect_quad(*origin, *u, *v, *normal, *d, my_index, ray, range),
-            NaiveGeometry::Triangle { p1, p2, p3 } => {
-                Self::intersect_triangle(*p1, *p2, *p3, my_index, ray, range)
+            NaiveGeometry::Triangle {
+                p1,
+                p2,
+                p3,
+                mesh,
+                triangle,
+            } => {
+                let mut hit = Self::intersect_triangle(*p1, *p2, *p3, my_index, ray, range)?;
+                hit.interpolate_mesh_attributes(mesh, *triangle, &Transform::IDENTITY);
+                Some(hit)
             }
             NaiveGeometry::Box { origin, u, v, w } => {
                 Self::intersect_box(*origin, *u, *v, *w, my_index, ray, range)
             }
+            NaiveGeometry::Instance { transform, tracer } => {
+                let mut hit = tracer.trace(&transform.ray_to_object(ray), range)?;
+                hit.point = ray.at(hit.distance);
+                hit.normal = transform.normal_to_world(hit.normal);
+                hit.shading_normal = transform.normal_to_world(hit.shading_normal);
+                hit.tangent = transform.vector_to_world(hit.tangent);
+                hit.bitangent = transform.vector_to_world(hit.bitangent);
+                hit.geometry_index = my_index;
+                Some(hit)
+            }
         }
     }
 
-    fn intersect_sphere(
+    /// Cheaper version of [`NaiveObject::hit`] that only checks if there is a hit.
+    pub(super) fn occludes(&self, ray: &Ray, range: &Range<f32>) -> bool {
+        // Masks need the texture coordinates of the hits
+        if self.mask.is_some() {
+            return self.hit(self.geometry_index, ray, range).is_some();
+        }
+
+        match &self.geometry {
+            NaiveGeometry::Sphere { center, radius } => {
+                Self::sphere_distance(*center, *radius, ray, range).is_some()
+            }
+            NaiveGeometry::Quad {
+                origin,
+                u,
+                v,
+                normal,
+                d,
+            } => Self::quad_distance(*origin, *u, *v, *normal, *d, ray, range).is_some(),
+            NaiveGeometry::Triangle { p1, p2, p3, .. } => {
+                Self::triangle_distance(*p1, *p2, *p3, ray, range).is_some()
+            }
+            NaiveGeometry::Box { origin, u, v, w } => {
+                Self::box_distance(*origin, *u, *v, *w, ray, range).is_some()
+            }
+            NaiveGeometry::Instance { transform, tracer } => {
+                tracer.occluded(&transform.ray_to_object(ray), range)
+            }
+        }
+    }
+
+    fn sphere_distance(
         center: Vec3,
         radius: f32,
-        geometry_index: usize,
         ray: &Ray,
         range: &impl RangeBounds<f32>,
-    ) -> Option<TraceResult> {
+    ) -> Option<f32> {
         let oc = ray.origin - center;
         let a = ray.direction.dot(ray.direction);
         let b = 2.0 * oc.dot(ray.direction);
@@ -171,38 +371,51 @@ impl NaiveObject {
             return None;
         };
 
+        Some(t)
+    }
+
+    fn intersect_sphere(
+        center: Vec3,
+        radius: f32,
+        geometry_index: usize,
+        ray: &Ray,
+        range: &impl RangeBounds<f32>,
+    ) -> Option<TraceResult> {
+        let t = Self::sphere_distance(center, radius, ray, range)?;
+
         let point = ray.at(t);
         let normal = (point - center).normalize();
 
         let front_face = ray.direction.dot(normal) < 0.0;
         let normal = if front_face { normal } else { -normal };
 
-        let theta = (-point.y).acos();
-        let phi = (-point.z).atan2(point.x) + PI;
-
-        let u = phi / (2.0 * PI);
-        let v = theta / PI;
+        let coordinates = SurfaceCoordinates::on_sphere(center, radius, point);
 
         Some(TraceResult {
             distance: t,
             point,
             normal,
+            shading_normal: normal,
+            tangent: coordinates.tangent,
+            bitangent: coordinates.bitangent,
+            vertex_color: Vec4::ONE,
             geometry_index,
             front_face,
-            uv: (u, v),
+            uv: coordinates.uv,
+            uv_derivatives: None,
         })
     }
 
-    fn intersect_quad(
+    /// Returns the distance and the hit coordinates along `u` and `v`.
+    fn quad_distance(
         origin: Vec3,
         u: Vec3,
         v: Vec3,
         normal: Vec3,
         d: f32,
-        geometry_index: usize,
         ray: &Ray,
         range: &impl RangeBounds<f32>,
-    ) -> Option<TraceResult> {
+    ) -> Option<(f32, f32, f32)> {
         let denominator = normal.dot(ray.direction);
 
         if denominator.abs() < 1e-6 {
@@ -224,6 +437,23 @@ impl NaiveObject {
             return None;
         }
 
+        Some((distance, u_coord, v_coord))
+    }
+
+    fn intersect_quad(
+        origin: Vec3,
+        u: Vec3,
+        v: Vec3,
+        normal: Vec3,
+        d: f32,
+        geometry_index: usize,
+        ray: &Ray,
+        range: &impl RangeBounds<f32>,
+    ) -> Option<TraceResult> {
+        let (distance, u_coord, v_coord) =
+            Self::quad_distance(origin, u, v, normal, d, ray, range)?;
+
+        let point = ray.at(distance);
         let front_face = ray.direction.dot(normal) < 0.0;
 
         let hit_normal = if front_face { normal } else { -normal };
@@ -232,20 +462,25 @@ impl NaiveObject {
             distance,
             point,
             normal: hit_normal,
+            shading_normal: hit_normal,
+            tangent: u,
+            bitangent: v,
+            vertex_color: Vec4::ONE,
             geometry_index,
             front_face,
             uv: (u_coord, v_coord),
+            uv_derivatives: None,
         })
     }
 
-    fn intersect_triangle(
+    /// Returns the distance and the barycentric coordinates of the hit.
+    fn triangle_distance(
         p1: Vec3,
         p2: Vec3,
         p3: Vec3,
-        geometry_index: usize,
         ray: &Ray,
         range: &impl RangeBounds<f32>,
-    ) -> Option<TraceResult> {
+    ) -> Option<(f32, f32, f32)> {
         let e1 = p2 - p1;
         let e2 = p3 - p1;
         let h = ray.direction.cross(e2);
@@ -272,31 +507,50 @@ impl NaiveObject {
             return None;
         }
 
+        Some((t, u, v))
+    }
+
+    fn intersect_triangle(
+        p1: Vec3,
+        p2: Vec3,
+        p3: Vec3,
+        geometry_index: usize,
+        ray: &Ray,
+        range: &impl RangeBounds<f32>,
+    ) -> Option<TraceResult> {
+        let (t, u, v) = Self::triangle_distance(p1, p2, p3, ray, range)?;
+
         let point = ray.at(t);
-        let normal = (e1.cross(e2)).normalize();
+        let normal = ((p2 - p1).cross(p3 - p1)).normalize();
 
         let front_face = ray.direction.dot(normal) < 0.0;
         let normal = if front_face { normal } else { -normal };
 
+        // Meshes replace these with their texture coordinates and tangents
         Some(TraceResult {
             distance: t,
             point,
             normal,
+            shading_normal: normal,
+            tangent: p2 - p1,
+            bitangent: p3 - p1,
+            vertex_color: Vec4::ONE,
             geometry_index,
             front_face,
             uv: (u, v),
+            uv_derivatives: None,
         })
     }
 
-    fn intersect_box(
+    /// Returns the distance and the hit point in box space, where the box spans [0, 1]³.
+    fn box_distance(
         origin: Vec3,
         u: Vec3,
         v: Vec3,
         w: Vec3,
-        geometry_index: usize,
         ray: &Ray,
         range: &impl RangeBounds<f32>,
-    ) -> Option<TraceResult> {
+    ) -> Option<(f32, Vec3)> {
         let ray_origin_local = ray.origin - origin;
 
         let det = u.dot(v.cross(w));
@@ -363,25 +617,23 @@ impl NaiveObject {
             return None;
         };
 
-        let point = ray.at(t);
+        Some((t, local_origin + t * local_direction))
+    }
 
-        let local_hit = local_origin + t * local_direction;
-
-        let eps = 1e-6;
-        let local_normal = if (local_hit.x).abs() < eps {
-            Vec3::new(-1.0, 0.0, 0.0) // Left face
-        } else if (local_hit.x - 1.0).abs() < eps {
-            Vec3::new(1.0, 0.0, 0.0) // Right face
-        } else if (local_hit.y).abs() < eps {
-            Vec3::new(0.0, -1.0, 0.0) // Bottom face
-        } else if (local_hit.y - 1.0).abs() < eps {
-            Vec3::new(0.0, 1.0, 0.0) // Top face
-        } else if (local_hit.z).abs() < eps {
-            Vec3::new(0.0, 0.0, -1.0) // Front face
-        } else {
-            Vec3::new(0.0, 0.0, 1.0) // Back face
-        };
+    fn intersect_box(
+        origin: Vec3,
+        u: Vec3,
+        v: Vec3,
+        w: Vec3,
+        geometry_index: usize,
+        ray: &Ray,
+        range: &impl RangeBounds<f32>,
+    ) -> Option<TraceResult> {
+        let (t, local_hit) = Self::box_distance(origin, u, v, w, ray, range)?;
+
+        let point = ray.at(t);
 
+        let local_normal = box_face(local_hit);
         let world_normal =
             (local_normal.x * u + local_normal.y * v + local_normal.z * w).normalize();
 
@@ -392,24 +644,20 @@ impl NaiveObject {
             -world_normal
         };
 
-        let (uv_u, uv_v) = if local_normal.x.abs() > 0.5 {
-            // X face - use Y and Z
-            (local_hit.z, local_hit.y)
-        } else if local_normal.y.abs() > 0.5 {
-            // Y face - use X and Z
-            (local_hit.x, local_hit.z)
-        } else {
-            // Z face - use X and Y
-            (local_hit.x, local_hit.y)
-        };
+        let coordinates = SurfaceCoordinates::on_box(u, v, w, local_hit);
 
         Some(TraceResult {
             distance: t,
             point,
             normal,
+            shading_normal: normal,
+            tangent: coordinates.tangent,
+            bitangent: coordinates.bitangent,
+            vertex_color: Vec4::ONE,
             geometry_index,
             front_face,
-            uv: (uv_u, uv_v),
+            uv: coordinates.uv,
+            uv_derivatives: None,
         })
     }
 }
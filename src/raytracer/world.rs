@@ -1,15 +1,31 @@
+use crate::raytracer::environment::Environment;
+use crate::raytracer::loader::{CameraSettings, TransformSettings, WorldSettings};
 use crate::raytracer::material::MaterialType;
-use glam::Vec3;
+use crate::raytracer::medium::Medium;
+use glam::{Affine3A, Mat3, Vec3};
 use serde::{Deserialize, Serialize};
+use std::ops::Range;
+use std::sync::Arc;
 
 pub struct Ray {
     pub origin: Vec3,
     pub direction: Vec3,
+    /// Wavelength in nanometers a dispersive material restricted the path to, or `None` while
+    /// it carries every wavelength.
+    pub wavelength: Option<f32>,
+    /// Rays through the neighbouring pixels, to estimate how much of a surface the pixel covers.
+    /// `None` once the path is too blurry for it to matter.
+    pub differentials: Option<RayDifferentials>,
 }
 
 impl Ray {
     pub fn new(origin: Vec3, direction: Vec3) -> Self {
-        Self { origin, direction }
+        Self {
+            origin,
+            direction,
+            wavelength: None,
+            differentials: None,
+        }
     }
 
     pub fn at(&self, t: f32) -> Vec3 {
@@ -17,9 +33,148 @@ impl Ray {
     }
 }
 
+/// A ray offset by one pixel to the right and one pixel down.
+#[derive(Debug, Clone, Copy)]
+pub struct RayDifferentials {
+    pub x_origin: Vec3,
+    pub x_direction: Vec3,
+    pub y_origin: Vec3,
+    pub y_direction: Vec3,
+}
+
+impl RayDifferentials {
+    /// Offsets from `point` to where the offset rays cross the plane through it with `normal`.
+    pub fn plane_offsets(&self, point: Vec3, normal: Vec3) -> Option<(Vec3, Vec3)> {
+        let offset = |origin: Vec3, direction: Vec3| {
+            let cos = normal.dot(direction);
+            if cos.abs() < 1e-8 {
+                return None;
+            }
+            let t = normal.dot(point - origin) / cos;
+            Some(origin + t * direction - point)
+        };
+        Some((
+            offset(self.x_origin, self.x_direction)?,
+            offset(self.y_origin, self.y_direction)?,
+        ))
+    }
+
+    /// Differentials of the ray leaving `point` towards `outgoing` after a mirror reflection
+    /// or a refraction of `incoming`, with the surface taken as flat around the point. The
+    /// part of the offset directions along the surface is scaled as the one of the ray was,
+    /// which is Snell's law with the ratio of refractive indices the ray went through.
+    pub fn specular(
+        &self,
+        point: Vec3,
+        normal: Vec3,
+        incoming: Vec3,
+        outgoing: Vec3,
+    ) -> Option<Self> {
+        let (dpdx, dpdy) = self.plane_offsets(point, normal)?;
+        let along_surface = |direction: Vec3| direction - normal * normal.dot(direction);
+        let (incoming, outgoing) = (incoming.normalize(), outgoing.normalize());
+        let incoming_along = along_surface(incoming).length();
+        let ratio = if incoming_along > 1e-4 {
+            along_surface(outgoing).length() / incoming_along
+        } else {
+            1.0
+        };
+        let side = normal.dot(outgoing).signum();
+
+        let bend = |direction: Vec3| {
+            let tangential = along_surface(direction.normalize()) * ratio;
+            let cos_squared = 1.0 - tangential.length_squared();
+            (cos_squared >= 0.0).then(|| tangential + side * cos_squared.sqrt() * normal)
+        };
+        Some(Self {
+            x_origin: point + dpdx,
+            x_direction: bend(self.x_direction)?,
+            y_origin: point + dpdy,
+            y_direction: bend(self.y_direction)?,
+        })
+    }
+}
+
+/// Axis-aligned bounding box.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Aabb {
+    pub const EMPTY: Aabb = Aabb {
+        min: Vec3::INFINITY,
+        max: Vec3::NEG_INFINITY,
+    };
+
+    pub fn from_points(points: impl IntoIterator<Item = Vec3>) -> Self {
+        points
+            .into_iter()
+            .fold(Self::EMPTY, |aabb, point| aabb.grow(point))
+    }
+
+    pub fn grow(self, point: Vec3) -> Self {
+        Self {
+            min: self.min.min(point),
+            max: self.max.max(point),
+        }
+    }
+
+    pub fn union(self, other: Aabb) -> Self {
+        Self {
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+        }
+    }
+
+    pub fn centroid(&self) -> Vec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        let extent = (self.max - self.min).max(Vec3::ZERO);
+        2.0 * (extent.x * extent.y + extent.y * extent.z + extent.z * extent.x)
+    }
+
+    pub fn corners(&self) -> [Vec3; 8] {
+        let (min, max) = (self.min, self.max);
+        [
+            min,
+            Vec3::new(max.x, min.y, min.z),
+            Vec3::new(min.x, max.y, min.z),
+            Vec3::new(min.x, min.y, max.z),
+            Vec3::new(max.x, max.y, min.z),
+            Vec3::new(max.x, min.y, max.z),
+            Vec3::new(min.x, max.y, max.z),
+            max,
+        ]
+    }
+
+    /// Returns the distance at which the ray enters the box, or `None` if it misses it inside `range`.
+    pub fn intersect(&self, ray: &Ray, inv_direction: Vec3, range: &Range<f32>) -> Option<f32> {
+        let t0 = (self.min - ray.origin) * inv_direction;
+        let t1 = (self.max - ray.origin) * inv_direction;
+
+        let t_enter = t0.min(t1).max_element().max(range.start);
+        let t_exit = t0.max(t1).min_element().min(range.end);
+
+        (t_enter <= t_exit).then_some(t_enter)
+    }
+}
+
 #[derive(Clone, Serialize, Deserialize)]
+#[serde(try_from = "WorldSettings")]
 pub struct World {
     pub geometry: Vec<Geometry>,
+    #[serde(default)]
+    pub environment: Environment,
+    /// Medium filling the space outside of every geometry.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fog: Option<Medium>,
+    /// First camera of the imported scenes, used when the scene file has none.
+    #[serde(skip)]
+    pub imported_camera: Option<CameraSettings>,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -28,6 +183,94 @@ pub struct Geometry {
     pub geometry_type: GeometryType,
     #[serde(flatten)]
     pub material: MaterialType,
+    /// Medium filling the inside of the geometry, which must then be closed. Rays enter it
+    /// through the surface, so its material is usually a dielectric or an interface.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub medium: Option<Medium>,
+    #[serde(default, skip_serializing_if = "Transform::is_identity")]
+    pub transform: Transform,
+}
+
+impl Geometry {
+    /// Bounds of the geometry after its transform is applied.
+    pub fn bounds(&self) -> Aabb {
+        self.transform.transform_aabb(self.geometry_type.bounds())
+    }
+}
+
+/// Places a geometry in the world, on top of the coordinates it is defined with.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "TransformSettings", into = "TransformSettings")]
+pub struct Transform {
+    pub object_to_world: Affine3A,
+    pub world_to_object: Affine3A,
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self::IDENTITY
+    }
+}
+
+impl Transform {
+    pub const IDENTITY: Transform = Transform {
+        object_to_world: Affine3A::IDENTITY,
+        world_to_object: Affine3A::IDENTITY,
+    };
+
+    pub fn new(object_to_world: Affine3A) -> Self {
+        Self {
+            object_to_world,
+            world_to_object: object_to_world.inverse(),
+        }
+    }
+
+    pub fn is_identity(&self) -> bool {
+        self.object_to_world == Affine3A::IDENTITY
+    }
+
+    pub fn point_to_world(&self, point: Vec3) -> Vec3 {
+        self.object_to_world.transform_point3(point)
+    }
+
+    pub fn vector_to_world(&self, vector: Vec3) -> Vec3 {
+        self.object_to_world.transform_vector3(vector)
+    }
+
+    /// The scale factor if the transform preserves shapes (no shearing or non-uniform scale).
+    pub fn uniform_scale(&self) -> Option<f32> {
+        let matrix = Mat3::from(self.object_to_world.matrix3);
+        let scale_squared = matrix.x_axis.length_squared();
+        let difference =
+            matrix.transpose() * matrix - Mat3::from_diagonal(Vec3::splat(scale_squared));
+        let tolerance = 1e-5 * scale_squared;
+        difference
+            .to_cols_array()
+            .iter()
+            .all(|value| value.abs() <= tolerance)
+            .then(|| scale_squared.sqrt())
+    }
+
+    /// Moves the ray into object space. The direction is not normalized, so distances
+    /// along the ray are the same in both spaces.
+    pub fn ray_to_object(&self, ray: &Ray) -> Ray {
+        Ray::new(
+            self.world_to_object.transform_point3(ray.origin),
+            self.world_to_object.transform_vector3(ray.direction),
+        )
+    }
+
+    /// Normals are transformed by the inverse transpose to stay perpendicular to the surface.
+    pub fn normal_to_world(&self, normal: Vec3) -> Vec3 {
+        (Mat3::from(self.world_to_object.matrix3).transpose() * normal).normalize()
+    }
+
+    pub fn transform_aabb(&self, aabb: Aabb) -> Aabb {
+        if self.is_identity() || aabb == Aabb::EMPTY {
+            return aabb;
+        }
+        Aabb::from_points(aabb.corners().map(|corner| self.point_to_world(corner)))
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -46,7 +289,7 @@ pub enum GeometryType {
         deserialize_with = "crate::raytracer::loader::deserialize_triangle_mesh",
         serialize_with = "crate::raytracer::loader::serialize_triangle_mesh"
     )]
-    TriangleMesh(TriangleMeshGeometry),
+    TriangleMesh(Arc<TriangleMeshGeometry>),
     Box {
         origin: Vec3,
         u: Vec3,
@@ -55,10 +298,108 @@ pub enum GeometryType {
     },
 }
 
+impl GeometryType {
+    pub fn bounds(&self) -> Aabb {
+        match self {
+            GeometryType::Sphere { center, radius } => Aabb {
+                min: center - Vec3::splat(*radius),
+                max: center + Vec3::splat(*radius),
+            },
+            GeometryType::Quad { origin, u, v } => {
+                Aabb::from_points([*origin, origin + u, origin + v, origin + u + v])
+            }
+            GeometryType::TriangleMesh(mesh) => {
+                Aabb::from_points(mesh.verts.iter().map(|&vert| Vec3::from(vert)))
+            }
+            GeometryType::Box { origin, u, v, w } => Aabb::from_points([
+                *origin,
+                origin + u,
+                origin + v,
+                origin + w,
+                origin + u + v,
+                origin + u + w,
+                origin + v + w,
+                origin + u + v + w,
+            ]),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct TriangleMeshGeometry {
     pub verts: Vec<(f32, f32, f32)>,
     pub indices: Vec<(u32, u32, u32)>,
-    // TODO: Add texture coordinates to uv mapping
-    // pub tex_coords: Vec<Vec2>,
+    /// Per vertex shading normals, or empty to shade with the flat triangle normals.
+    pub normals: Vec<(f32, f32, f32)>,
+    /// Per vertex texture coordinates, or empty to use the barycentric coordinates as UVs.
+    pub tex_coords: Vec<(f32, f32)>,
+    /// Per vertex linear RGB colors tinting the material, or empty to leave it unchanged.
+    pub colors: Vec<(f32, f32, f32)>,
+}
+
+impl TriangleMeshGeometry {
+    /// Interpolates the vertex normals of `triangle` at the barycentric coordinates `(u, v)`
+    /// of its second and third vertices.
+    pub fn shading_normal(&self, triangle: usize, (u, v): (f32, f32)) -> Option<Vec3> {
+        if self.normals.is_empty() {
+            return None;
+        }
+
+        self.interpolate(&self.normals, triangle, (u, v))
+            .try_normalize()
+    }
+
+    /// Interpolates the vertex colors of `triangle`, if the mesh has any.
+    pub fn vertex_color(&self, triangle: usize, (u, v): (f32, f32)) -> Option<Vec3> {
+        if self.colors.is_empty() {
+            return None;
+        }
+        Some(self.interpolate(&self.colors, triangle, (u, v)))
+    }
+
+    fn interpolate(&self, values: &[(f32, f32, f32)], triangle: usize, (u, v): (f32, f32)) -> Vec3 {
+        let (a, b, c) = self.indices[triangle];
+        Vec3::from(values[a as usize]) * (1.0 - u - v)
+            + Vec3::from(values[b as usize]) * u
+            + Vec3::from(values[c as usize]) * v
+    }
+
+    /// Derivatives of the points of `triangle` along its texture coordinates. Without texture
+    /// coordinates, or when they are degenerate, they are the edges from its first vertex.
+    pub fn tangents(&self, triangle: usize) -> (Vec3, Vec3) {
+        let (a, b, c) = self.indices[triangle];
+        let [pa, pb, pc] = [a, b, c].map(|index| Vec3::from(self.verts[index as usize]));
+        let (edge1, edge2) = (pb - pa, pc - pa);
+        if self.tex_coords.is_empty() {
+            return (edge1, edge2);
+        }
+
+        let [ta, tb, tc] = [a, b, c].map(|index| self.tex_coords[index as usize]);
+        let (du1, dv1) = (tb.0 - ta.0, tb.1 - ta.1);
+        let (du2, dv2) = (tc.0 - ta.0, tc.1 - ta.1);
+        let determinant = du1 * dv2 - dv1 * du2;
+        if determinant.abs() < 1e-12 {
+            return (edge1, edge2);
+        }
+        (
+            (edge1 * dv2 - edge2 * dv1) / determinant,
+            (edge2 * du1 - edge1 * du2) / determinant,
+        )
+    }
+
+    /// Interpolates the texture coordinates of `triangle`, falling back to the barycentric
+    /// coordinates when the mesh has none.
+    pub fn tex_coord(&self, triangle: usize, (u, v): (f32, f32)) -> (f32, f32) {
+        if self.tex_coords.is_empty() {
+            return (u, v);
+        }
+
+        let (a, b, c) = self.indices[triangle];
+        let [ta, tb, tc] = [a, b, c].map(|index| self.tex_coords[index as usize]);
+        let w = 1.0 - u - v;
+        (
+            ta.0 * w + tb.0 * u + tc.0 * v,
+            ta.1 * w + tb.1 * u + tc.1 * v,
+        )
+    }
 }